@@ -0,0 +1,592 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Local, Utc};
+use log::error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use warp::http::{HeaderMap, Version};
+
+/// Apache's Common Log Format.
+pub const COMMON: &str = "%h %l %u %t \"%r\" %>s %b";
+
+/// Apache's Combined Log Format: Common plus referer and user agent.
+pub const COMBINED: &str = "%h %l %u %t \"%r\" %>s %b \"%{Referer}i\" \"%{User-Agent}i\"";
+
+/// One piece of an access log format string.
+#[derive(Debug, Clone)]
+enum Token {
+    Literal(String),
+    /// `%h`: the client address.
+    RemoteHost,
+    /// `%l`: the remote logname, always `-`.
+    Ident,
+    /// `%u`: the user of Basic authentication, or `-`.
+    User,
+    /// `%t`: the time the request arrived.
+    Time,
+    /// `%r`: the request line.
+    RequestLine,
+    /// `%s` / `%>s`: the response status.
+    Status,
+    /// `%b`: the response body size, `-` for none.
+    BytesClf,
+    /// `%B`: the response body size.
+    Bytes,
+    /// `%m`, `%U`, `%q`, `%H`: method, path, query (with `?`), protocol.
+    Method,
+    Path,
+    Query,
+    Protocol,
+    /// `%D` / `%T`: the time taken, in microseconds / seconds.
+    Micros,
+    Seconds,
+    /// `%{Name}i` / `%{Name}o`: a request / response header.
+    RequestHeader(String),
+    ResponseHeader(String),
+}
+
+/// A parsed access log format.
+#[derive(Debug, Clone)]
+pub struct Format(Vec<Token>);
+
+/// Parses an Apache-style format string. `common` and `combined` name the
+/// standard formats.
+pub fn parse_format(format: &str) -> Result<Format, String> {
+    let format = match format {
+        "common" => COMMON,
+        "combined" => COMBINED,
+        custom => custom,
+    };
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+        let mut directive = chars
+            .next()
+            .ok_or_else(|| format!("access log format {:?} ends with '%'", format))?;
+        let mut name = None;
+        if directive == '%' {
+            literal.push('%');
+            continue;
+        }
+        if directive == '>' {
+            directive = chars.next().unwrap_or('>');
+        }
+        if directive == '{' {
+            let header: String = chars.by_ref().take_while(|c| *c != '}').collect();
+            name = Some(header);
+            directive = chars
+                .next()
+                .ok_or_else(|| format!("access log format {:?} ends after %{{...}}", format))?;
+        }
+        let token = match (directive, name) {
+            ('h', None) => Token::RemoteHost,
+            ('l', None) => Token::Ident,
+            ('u', None) => Token::User,
+            ('t', None) => Token::Time,
+            ('r', None) => Token::RequestLine,
+            ('s', None) => Token::Status,
+            ('b', None) => Token::BytesClf,
+            ('B', None) => Token::Bytes,
+            ('m', None) => Token::Method,
+            ('U', None) => Token::Path,
+            ('q', None) => Token::Query,
+            ('H', None) => Token::Protocol,
+            ('D', None) => Token::Micros,
+            ('T', None) => Token::Seconds,
+            ('i', Some(name)) => Token::RequestHeader(name),
+            ('o', Some(name)) => Token::ResponseHeader(name),
+            (directive, _) => {
+                return Err(format!(
+                    "unknown directive %{} in access log format {:?}",
+                    directive, format
+                ))
+            }
+        };
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(std::mem::take(&mut literal)));
+        }
+        tokens.push(token);
+    }
+    if !literal.is_empty() {
+        tokens.push(Token::Literal(literal));
+    }
+    Ok(Format(tokens))
+}
+
+/// Checks `--access-log-keep`: rotation always keeps the previous file.
+pub fn check_keep(keep: usize) -> Result<usize, String> {
+    if keep == 0 {
+        return Err("invalid access log keep 0: keep at least 1 rotated file".to_string());
+    }
+    Ok(keep)
+}
+
+/// Parses a size like `10MB`, `512K` or `1048576`.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let trimmed = size.trim();
+    let digits = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(digits);
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size {:?}: use e.g. 10MB or 512K", size)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .map(|n| n * multiplier)
+        .ok_or_else(|| format!("invalid size {:?}: use e.g. 10MB or 512K", size))
+}
+
+/// How often the access log starts a new file, whatever its size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Hourly,
+    Daily,
+}
+
+pub fn parse_period(period: &str) -> Result<Period, String> {
+    match period {
+        "hourly" => Ok(Period::Hourly),
+        "daily" => Ok(Period::Daily),
+        other => Err(format!(
+            "invalid access log rotation {:?}: use hourly or daily",
+            other
+        )),
+    }
+}
+
+impl Period {
+    /// Names the period a moment falls in; the file rotates when it changes.
+    fn of(self, time: DateTime<Local>) -> String {
+        match self {
+            Period::Hourly => time.format("%Y-%m-%d %H").to_string(),
+            Period::Daily => time.format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
+/// When to move the access log aside and start a new one.
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub period: Option<Period>,
+    /// Rotated files kept, as `access.log.1` (newest) to `access.log.<keep>`;
+    /// at least 1 (see `check_keep`).
+    pub keep: usize,
+}
+
+/// What the access log needs to know about an exchange.
+pub struct Exchange<'a> {
+    pub remote: Option<SocketAddr>,
+    pub time: DateTime<Utc>,
+    pub method: &'a str,
+    pub version: Version,
+    pub path: &'a str,
+    pub query: &'a str,
+    pub request_headers: &'a HeaderMap,
+    pub status: u16,
+    pub response_headers: &'a HeaderMap,
+    pub response_len: usize,
+    pub duration: Duration,
+}
+
+/// An access log file (`--access-log`), one line per exchange.
+#[derive(Debug)]
+pub struct AccessLog {
+    format: Format,
+    file: Mutex<LogFile>,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    rotation: Rotation,
+    period: Option<String>,
+}
+
+impl AccessLog {
+    pub fn open(path: &str, format: Format, rotation: Rotation) -> io::Result<AccessLog> {
+        let path = PathBuf::from(path);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // A file left by an earlier run belongs to the period it was last written in.
+        let period = rotation.period.map(|period| {
+            let modified = metadata
+                .modified()
+                .map(DateTime::<Local>::from)
+                .unwrap_or_else(|_| Local::now());
+            period.of(modified)
+        });
+        Ok(AccessLog {
+            format,
+            file: Mutex::new(LogFile {
+                path,
+                file,
+                size: metadata.len(),
+                rotation,
+                period,
+            }),
+        })
+    }
+
+    /// Appends the line for an exchange, rotating the file first if it's due.
+    pub fn write(&self, exchange: &Exchange) {
+        let mut line = self.format_line(exchange);
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_line(line.as_bytes()) {
+            error!(
+                "Failed to write to access log {}: {}",
+                file.path.display(),
+                e
+            );
+        }
+    }
+
+    fn format_line(&self, exchange: &Exchange) -> String {
+        let mut line = String::new();
+        for token in &self.format.0 {
+            match token {
+                Token::Literal(text) => line.push_str(text),
+                Token::RemoteHost => match exchange.remote {
+                    Some(addr) => line.push_str(&addr.ip().to_string()),
+                    None => line.push('-'),
+                },
+                Token::Ident => line.push('-'),
+                Token::User => line.push_str(&escape(
+                    &basic_auth_user(exchange.request_headers).unwrap_or_else(|| "-".to_string()),
+                )),
+                Token::Time => line.push_str(
+                    &exchange
+                        .time
+                        .with_timezone(&Local)
+                        .format("[%d/%b/%Y:%H:%M:%S %z]")
+                        .to_string(),
+                ),
+                Token::RequestLine => {
+                    line.push_str(&escape(exchange.method));
+                    line.push(' ');
+                    line.push_str(&escape(exchange.path));
+                    if !exchange.query.is_empty() {
+                        line.push('?');
+                        line.push_str(&escape(exchange.query));
+                    }
+                    line.push(' ');
+                    line.push_str(protocol(exchange.version));
+                }
+                Token::Status => line.push_str(&exchange.status.to_string()),
+                Token::BytesClf if exchange.response_len == 0 => line.push('-'),
+                Token::BytesClf | Token::Bytes => line.push_str(&exchange.response_len.to_string()),
+                Token::Method => line.push_str(&escape(exchange.method)),
+                Token::Path => line.push_str(&escape(exchange.path)),
+                Token::Query if exchange.query.is_empty() => {}
+                Token::Query => {
+                    line.push('?');
+                    line.push_str(&escape(exchange.query));
+                }
+                Token::Protocol => line.push_str(protocol(exchange.version)),
+                Token::Micros => line.push_str(&exchange.duration.as_micros().to_string()),
+                Token::Seconds => line.push_str(&exchange.duration.as_secs().to_string()),
+                Token::RequestHeader(name) => {
+                    line.push_str(&header(exchange.request_headers, name))
+                }
+                Token::ResponseHeader(name) => {
+                    line.push_str(&header(exchange.response_headers, name))
+                }
+            }
+        }
+        line
+    }
+}
+
+impl LogFile {
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let period = self.rotation.period.map(|period| period.of(Local::now()));
+        let too_big = self
+            .rotation
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + line.len() as u64 > max);
+        if period != self.period {
+            if self.size > 0 {
+                self.rotate()?;
+            }
+            self.period = period;
+        } else if too_big {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts `access.log.N` to `access.log.N+1` (dropping the oldest), moves
+    /// the current file to `access.log.1` and starts a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = self.rotation.keep;
+        let numbered = |n: usize| numbered_path(&self.path, n);
+        let _ = fs::remove_file(numbered(keep));
+        for n in (1..keep).rev() {
+            let from = numbered(n);
+            if from.exists() {
+                fs::rename(&from, numbered(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, numbered(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn numbered_path(path: &Path, n: usize) -> PathBuf {
+    let mut numbered = path.as_os_str().to_owned();
+    numbered.push(format!(".{}", n));
+    PathBuf::from(numbered)
+}
+
+/// A header's value for the log, `-` if it's missing.
+/// The protocol as it appears in a request line, e.g. `HTTP/1.1`.
+fn protocol(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_2 => "HTTP/2.0",
+        Version::HTTP_3 => "HTTP/3.0",
+        _ => "HTTP/1.1",
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> String {
+    match headers.get(name) {
+        Some(value) => escape(&String::from_utf8_lossy(value.as_bytes())),
+        None => "-".to_string(),
+    }
+}
+
+/// The user name of a Basic `Authorization` header.
+fn basic_auth_user(headers: &HeaderMap) -> Option<String> {
+    let value = headers
+        .get(warp::http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    let (scheme, credentials) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = STANDARD.decode(credentials.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let user = decoded.split(':').next()?;
+    Some(user.to_string()).filter(|u| !u.is_empty())
+}
+
+/// Escapes quotes, backslashes and control characters the way Apache does,
+/// so a value can't break up a log line.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use warp::http::HeaderValue;
+
+    fn exchange_line(format: &str) -> String {
+        exchange_line_over(format, Version::HTTP_11)
+    }
+
+    fn exchange_line_over(format: &str, version: Version) -> String {
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert("user-agent", HeaderValue::from_static("curl/8 \"x\""));
+        // "ada:secret"
+        request_headers.insert(
+            "authorization",
+            HeaderValue::from_static("Basic YWRhOnNlY3JldA=="),
+        );
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert("x-proxxyy-cache", HeaderValue::from_static("HIT"));
+        let log = AccessLog {
+            format: parse_format(format).unwrap(),
+            file: Mutex::new(log_file(&TempDir::new("access-log").join("access.log"))),
+        };
+        log.format_line(&Exchange {
+            remote: Some("10.0.0.7:51234".parse().unwrap()),
+            time: Utc::now(),
+            method: "GET",
+            version,
+            path: "/v1/users",
+            query: "page=2",
+            request_headers: &request_headers,
+            status: 200,
+            response_headers: &response_headers,
+            response_len: 1234,
+            duration: Duration::from_micros(2_500_000),
+        })
+    }
+
+    #[test]
+    fn formats_directives() {
+        assert_eq!(
+            exchange_line("%h %l %u \"%r\" %>s %b %B"),
+            "10.0.0.7 - ada \"GET /v1/users?page=2 HTTP/1.1\" 200 1234 1234"
+        );
+        assert_eq!(
+            exchange_line("%m %U%q %H %D %T 100%%"),
+            "GET /v1/users?page=2 HTTP/1.1 2500000 2 100%"
+        );
+        assert_eq!(
+            exchange_line("\"%{User-Agent}i\" %{X-Proxxyy-Cache}o %{Referer}i"),
+            "\"curl/8 \\\"x\\\"\" HIT -"
+        );
+    }
+
+    #[test]
+    fn logs_the_request_protocol() {
+        assert_eq!(
+            exchange_line_over("\"%r\" %H", Version::HTTP_10),
+            "\"GET /v1/users?page=2 HTTP/1.0\" HTTP/1.0"
+        );
+        assert_eq!(exchange_line_over("%H", Version::HTTP_2), "HTTP/2.0");
+    }
+
+    #[test]
+    fn names_the_standard_formats() {
+        let common = exchange_line("common");
+        assert!(common.starts_with("10.0.0.7 - ada ["), "{}", common);
+        assert!(common.ends_with("] \"GET /v1/users?page=2 HTTP/1.1\" 200 1234"));
+        let combined = exchange_line("combined");
+        assert!(combined.starts_with(&common[..common.len() - 4]));
+        assert!(combined.ends_with(" 1234 \"-\" \"curl/8 \\\"x\\\"\""));
+    }
+
+    #[test]
+    fn rejects_bad_formats() {
+        for format in ["%", "%x", "%{Host", "%{Host}x", "%>"] {
+            assert!(
+                parse_format(format).is_err(),
+                "{:?} should not parse",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_at_least_one_rotated_file() {
+        assert_eq!(check_keep(1), Ok(1));
+        assert!(check_keep(0).is_err());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1048576"), Ok(1048576));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("10MB"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size(" 2 gb "), Ok(2 * 1024 * 1024 * 1024));
+        for size in ["", "0", "MB", "10TB", "1.5MB", "-1"] {
+            assert!(parse_size(size).is_err(), "{:?} should not parse", size);
+        }
+    }
+
+    fn rotation(max_size: Option<u64>, period: Option<Period>, keep: usize) -> Rotation {
+        Rotation {
+            max_size,
+            period,
+            keep,
+        }
+    }
+
+    fn log_file(path: &Path) -> LogFile {
+        LogFile {
+            path: path.to_path_buf(),
+            file: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .unwrap(),
+            size: 0,
+            rotation: rotation(None, None, 1),
+            period: None,
+        }
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_newest_files() {
+        let dir = TempDir::new("access-log");
+        let path = dir.join("access.log");
+        let mut file = log_file(&path);
+        file.rotation = rotation(Some(10), None, 2);
+        for line in ["one 1\n", "two 2\n", "three\n", "four\n"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+        assert_eq!(read(&path), "four\n");
+        assert_eq!(read(&numbered_path(&path, 1)), "three\n");
+        assert_eq!(read(&numbered_path(&path, 2)), "two 2\n");
+        assert!(!numbered_path(&path, 3).exists());
+
+        // A line bigger than the limit still goes into a file of its own.
+        file.write_line(b"a very long line\n").unwrap();
+        file.write_line(b"x\n").unwrap();
+        assert_eq!(read(&path), "x\n");
+        assert_eq!(read(&numbered_path(&path, 1)), "a very long line\n");
+        assert_eq!(read(&numbered_path(&path, 2)), "four\n");
+    }
+
+    #[test]
+    fn rotates_when_the_period_changes() {
+        let dir = TempDir::new("access-log");
+        let path = dir.join("access.log");
+        let mut file = log_file(&path);
+        file.rotation = rotation(None, Some(Period::Daily), 3);
+        file.period = Some(Period::Daily.of(Local::now()));
+        file.write_line(b"today\n").unwrap();
+        assert!(!numbered_path(&path, 1).exists());
+
+        // As if the file had been started yesterday.
+        file.period = Some("2000-01-01".to_string());
+        file.write_line(b"tomorrow\n").unwrap();
+        assert_eq!(read(&path), "tomorrow\n");
+        assert_eq!(read(&numbered_path(&path, 1)), "today\n");
+
+        // An empty file isn't rotated, it just starts the new period.
+        let path = dir.join("hourly.log");
+        let mut file = log_file(&path);
+        file.rotation = rotation(None, Some(Period::Hourly), 3);
+        file.period = Some("2000-01-01 00".to_string());
+        file.write_line(b"first\n").unwrap();
+        assert_eq!(read(&path), "first\n");
+        assert!(!numbered_path(&path, 1).exists());
+    }
+}
@@ -0,0 +1,477 @@
+use crate::journal::{self, Journal};
+use crate::mock::{Mock, MockSource, MockStore};
+use crate::Config;
+use bytes::Bytes;
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use warp::http::StatusCode;
+use warp::reply::{self, Reply, Response};
+use warp::Filter;
+
+/// What the admin API can see and change in the running proxy.
+#[derive(Debug)]
+pub struct Admin {
+    pub config: Arc<RwLock<Config>>,
+    pub mocks: Arc<MockStore>,
+    pub journal: Arc<Journal>,
+    /// The directory recording last used, so `POST /recording/start` can resume it.
+    last_recording_dir: RwLock<Option<String>>,
+}
+
+/// Options changeable at runtime through `PATCH /config`.
+#[derive(Debug, Deserialize)]
+struct ConfigPatch {
+    target_url: Option<String>,
+    show_headers: Option<bool>,
+    show_body: Option<bool>,
+}
+
+/// Body of `POST /recording/start`.
+#[derive(Debug, Deserialize, Default)]
+struct RecordingStart {
+    directory: Option<String>,
+}
+
+impl Admin {
+    pub fn new(config: Arc<RwLock<Config>>, mocks: Arc<MockStore>, journal: Arc<Journal>) -> Admin {
+        let last_recording_dir = config.read().unwrap().save_request_directory.clone();
+        Admin {
+            config,
+            mocks,
+            journal,
+            last_recording_dir: RwLock::new(last_recording_dir),
+        }
+    }
+
+    fn config_json(&self) -> Response {
+        let config = self.config.read().unwrap();
+        reply::json(&json!({
+            "target_url": config.target_url,
+            "show_headers": config.show_headers,
+            "show_body": config.show_body,
+            "recording": config.save_request_directory.is_some(),
+            "save_request_directory": config.save_request_directory,
+        }))
+        .into_response()
+    }
+
+    fn patch_config(&self, patch: ConfigPatch) -> Response {
+        if let Some(ref url) = patch.target_url {
+            if let Err(err) = crate::startup::check_url("target_url", url) {
+                return error(StatusCode::BAD_REQUEST, err);
+            }
+        }
+        {
+            let mut config = self.config.write().unwrap();
+            if let Some(url) = patch.target_url {
+                info!("Admin: switching target URL to {}", url);
+                config.target_url = Some(url);
+            }
+            if let Some(show_headers) = patch.show_headers {
+                info!("Admin: show_headers = {}", show_headers);
+                config.show_headers = show_headers;
+            }
+            if let Some(show_body) = patch.show_body {
+                info!("Admin: show_body = {}", show_body);
+                config.show_body = show_body;
+            }
+        }
+        self.config_json()
+    }
+
+    fn start_recording(&self, start: RecordingStart) -> Response {
+        let Some(dir) = start
+            .directory
+            .or_else(|| self.last_recording_dir.read().unwrap().clone())
+        else {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "no recording directory: pass {\"directory\": \"...\"}".to_string(),
+            );
+        };
+        if let Err(err) = crate::startup::ensure_writable_dir("recording directory", &dir) {
+            return error(StatusCode::BAD_REQUEST, err);
+        }
+        info!("Admin: recording requests to {}", dir);
+        *self.last_recording_dir.write().unwrap() = Some(dir.clone());
+        self.config.write().unwrap().save_request_directory = Some(dir);
+        self.config_json()
+    }
+
+    fn stop_recording(&self) -> Response {
+        info!("Admin: recording stopped");
+        self.config.write().unwrap().save_request_directory = None;
+        self.config_json()
+    }
+
+    fn add_mock(&self, mock: Mock) -> Response {
+        let mock = match prepared(mock) {
+            Ok(mock) => mock,
+            Err(err) => return error(StatusCode::BAD_REQUEST, err),
+        };
+        info!("Admin: adding mock {} {}", mock.method, mock.path);
+        let id = self.mocks.add(mock);
+        reply::with_status(reply::json(&json!({ "id": id })), StatusCode::CREATED).into_response()
+    }
+
+    fn update_mock(&self, id: u64, mock: Mock) -> Response {
+        let mock = match prepared(mock) {
+            Ok(mock) => mock,
+            Err(err) => return error(StatusCode::BAD_REQUEST, err),
+        };
+        info!(
+            "Admin: updating mock {} to {} {}",
+            id, mock.method, mock.path
+        );
+        if self.mocks.update(id, mock) {
+            reply::json(&json!({ "id": id })).into_response()
+        } else {
+            error(StatusCode::NOT_FOUND, format!("no mock with id {}", id))
+        }
+    }
+
+    fn delete_mock(&self, id: u64) -> Response {
+        if self.mocks.remove(id) {
+            info!("Admin: deleted mock {}", id);
+            StatusCode::NO_CONTENT.into_response()
+        } else {
+            error(StatusCode::NOT_FOUND, format!("no mock with id {}", id))
+        }
+    }
+
+    fn delete_admin_mocks(&self) -> Response {
+        info!("Admin: deleting all mocks added through the admin API");
+        self.mocks.replace_source(MockSource::Admin, Vec::new());
+        StatusCode::NO_CONTENT.into_response()
+    }
+
+    fn verify(&self) -> Response {
+        let verification = self.mocks.verify();
+        let status = if verification.ok {
+            StatusCode::OK
+        } else {
+            StatusCode::EXPECTATION_FAILED
+        };
+        reply::with_status(reply::json(&verification), status).into_response()
+    }
+
+    fn reset_hits(&self) -> Response {
+        info!("Admin: resetting mock hit counts");
+        self.mocks.reset_hits();
+        StatusCode::NO_CONTENT.into_response()
+    }
+
+    fn query_journal(&self, query: journal::Query) -> Response {
+        let entries = self.journal.query(&query);
+        reply::json(&json!({ "count": entries.len(), "entries": entries })).into_response()
+    }
+
+    fn clear_journal(&self) -> Response {
+        info!("Admin: clearing the request journal");
+        self.journal.clear();
+        StatusCode::NO_CONTENT.into_response()
+    }
+}
+
+/// Checks a mock sent to the admin API; relative body files are resolved
+/// against the working directory.
+fn prepared(mut mock: Mock) -> Result<Mock, String> {
+    mock.prepare(Path::new(".")).map(|_| mock)
+}
+
+/// Parses a JSON request body, whatever its declared content type.
+fn parse<T: DeserializeOwned>(body: &Bytes) -> Result<T, String> {
+    serde_json::from_slice(body).map_err(|e| format!("invalid JSON body: {}", e))
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    reply::with_status(reply::json(&json!({ "error": message })), status).into_response()
+}
+
+fn with_admin(
+    admin: Arc<Admin>,
+) -> impl Filter<Extract = (Arc<Admin>,), Error = Infallible> + Clone {
+    warp::any().map(move || admin.clone())
+}
+
+/// The admin API routes:
+///
+/// GET    /config               the runtime options
+/// PATCH  /config               change target_url, show_headers or show_body
+/// POST   /recording/start      save requests (optionally to {"directory": ...})
+/// POST   /recording/stop       stop saving requests
+/// GET    /mocks                every mock, in match order
+/// POST   /mocks                add a mock (JSON), matched before all others
+/// DELETE /mocks                delete the mocks added through the admin API
+/// PUT    /mocks/{id}           replace a mock
+/// DELETE /mocks/{id}           delete a mock
+/// GET    /verify               mocks whose times/at_least isn't met, and
+///                              requests no mock matched (417 if any unmet)
+/// DELETE /verify               reset hit counts and unmatched requests
+/// GET    /metrics              Prometheus metrics
+/// GET    /journal              recent requests, filtered by method, path,
+///                              status, outcome, since, until and limit
+/// DELETE /journal              forget the recorded requests
+pub fn routes(
+    admin: Arc<Admin>,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    let get_config = warp::path!("config")
+        .and(warp::get())
+        .and(with_admin(admin.clone()))
+        .map(|admin: Arc<Admin>| admin.config_json());
+    let patch_config = warp::path!("config")
+        .and(warp::patch())
+        .and(with_admin(admin.clone()))
+        .and(warp::body::bytes())
+        .map(|admin: Arc<Admin>, body: Bytes| match parse(&body) {
+            Ok(patch) => admin.patch_config(patch),
+            Err(err) => error(StatusCode::BAD_REQUEST, err),
+        });
+    let start_recording = warp::path!("recording" / "start")
+        .and(warp::post())
+        .and(with_admin(admin.clone()))
+        .and(warp::body::bytes())
+        .map(|admin: Arc<Admin>, body: Bytes| {
+            if body.is_empty() {
+                return admin.start_recording(RecordingStart::default());
+            }
+            match parse(&body) {
+                Ok(start) => admin.start_recording(start),
+                Err(err) => error(StatusCode::BAD_REQUEST, err),
+            }
+        });
+    let stop_recording = warp::path!("recording" / "stop")
+        .and(warp::post())
+        .and(with_admin(admin.clone()))
+        .map(|admin: Arc<Admin>| admin.stop_recording());
+    let list_mocks = warp::path!("mocks")
+        .and(warp::get())
+        .and(with_admin(admin.clone()))
+        .map(|admin: Arc<Admin>| reply::json(&admin.mocks.list()).into_response());
+    let add_mock = warp::path!("mocks")
+        .and(warp::post())
+        .and(with_admin(admin.clone()))
+        .and(warp::body::bytes())
+        .map(|admin: Arc<Admin>, body: Bytes| match parse(&body) {
+            Ok(mock) => admin.add_mock(mock),
+            Err(err) => error(StatusCode::BAD_REQUEST, err),
+        });
+    let delete_admin_mocks = warp::path!("mocks")
+        .and(warp::delete())
+        .and(with_admin(admin.clone()))
+        .map(|admin: Arc<Admin>| admin.delete_admin_mocks());
+    let update_mock = warp::path!("mocks" / u64)
+        .and(warp::put())
+        .and(with_admin(admin.clone()))
+        .and(warp::body::bytes())
+        .map(
+            |id: u64, admin: Arc<Admin>, body: Bytes| match parse(&body) {
+                Ok(mock) => admin.update_mock(id, mock),
+                Err(err) => error(StatusCode::BAD_REQUEST, err),
+            },
+        );
+    let delete_mock = warp::path!("mocks" / u64)
+        .and(warp::delete())
+        .and(with_admin(admin.clone()))
+        .map(|id: u64, admin: Arc<Admin>| admin.delete_mock(id));
+    let verify = warp::path!("verify")
+        .and(warp::get())
+        .and(with_admin(admin.clone()))
+        .map(|admin: Arc<Admin>| admin.verify());
+    let reset_hits = warp::path!("verify")
+        .and(warp::delete())
+        .and(with_admin(admin.clone()))
+        .map(|admin: Arc<Admin>| admin.reset_hits());
+    let metrics = warp::path!("metrics").and(warp::get()).map(|| {
+        reply::with_header(
+            crate::metrics::METRICS.render(),
+            "content-type",
+            "text/plain; version=0.0.4",
+        )
+        .into_response()
+    });
+    let query_journal = warp::path!("journal")
+        .and(warp::get())
+        .and(with_admin(admin.clone()))
+        .and(warp::query::<journal::Query>())
+        .map(|admin: Arc<Admin>, query: journal::Query| admin.query_journal(query));
+    let clear_journal = warp::path!("journal")
+        .and(warp::delete())
+        .and(with_admin(admin))
+        .map(|admin: Arc<Admin>| admin.clear_journal());
+
+    get_config
+        .or(patch_config)
+        .unify()
+        .or(start_recording)
+        .unify()
+        .or(stop_recording)
+        .unify()
+        .or(list_mocks)
+        .unify()
+        .or(add_mock)
+        .unify()
+        .or(delete_admin_mocks)
+        .unify()
+        .or(update_mock)
+        .unify()
+        .or(delete_mock)
+        .unify()
+        .or(verify)
+        .unify()
+        .or(reset_hits)
+        .unify()
+        .or(metrics)
+        .unify()
+        .or(query_journal)
+        .unify()
+        .or(clear_journal)
+        .unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use clap::Parser;
+    use serde_json::Value;
+
+    fn admin(mocks: Vec<Mock>) -> Arc<Admin> {
+        let config = Config::parse_from(["proxxyy", "-t", "http://localhost:6969"]);
+        Arc::new(Admin::new(
+            Arc::new(RwLock::new(config)),
+            Arc::new(MockStore::new(mocks)),
+            Arc::new(Journal::new(10)),
+        ))
+    }
+
+    async fn call(admin: &Arc<Admin>, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let response = warp::test::request()
+            .method(method)
+            .path(path)
+            .body(body)
+            .reply(&routes(admin.clone()))
+            .await;
+        let json = serde_json::from_slice(response.body()).unwrap_or(Value::Null);
+        (response.status().as_u16(), json)
+    }
+
+    #[tokio::test]
+    async fn adds_updates_and_deletes_mocks() {
+        let admin = admin(Vec::new());
+        let (status, created) = call(
+            &admin,
+            "POST",
+            "/mocks",
+            r#"{"method": "GET", "path": "/a", "body": "a"}"#,
+        )
+        .await;
+        assert_eq!(status, 201);
+        let id = created["id"].as_u64().unwrap();
+
+        let update = r#"{"method": "GET", "path": "/b", "body": "b"}"#;
+        let (status, _) = call(&admin, "PUT", &format!("/mocks/{}", id), update).await;
+        assert_eq!(status, 200);
+        let (_, mocks) = call(&admin, "GET", "/mocks", "").await;
+        assert_eq!(mocks[0]["path"], "/b");
+        let (status, body) = call(&admin, "PUT", "/mocks/999", update).await;
+        assert_eq!(
+            (status, body["error"].as_str()),
+            (404, Some("no mock with id 999"))
+        );
+
+        let (status, body) = call(&admin, "POST", "/mocks", r#"{"method": "GET"}"#).await;
+        assert_eq!(status, 400);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid JSON body"));
+        let bad_status = r#"{"method": "GET", "path": "/c", "status": 99}"#;
+        let (status, body) = call(&admin, "POST", "/mocks", bad_status).await;
+        assert_eq!(
+            (status, body["error"].as_str()),
+            (400, Some("invalid status code 99"))
+        );
+
+        let (status, _) = call(&admin, "DELETE", &format!("/mocks/{}", id), "").await;
+        assert_eq!(status, 204);
+        let (status, _) = call(&admin, "DELETE", &format!("/mocks/{}", id), "").await;
+        assert_eq!(status, 404);
+        assert!(admin.mocks.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn patches_the_config() {
+        let admin = admin(Vec::new());
+        let (status, body) = call(&admin, "PATCH", "/config", r#"{"target_url": "nope"}"#).await;
+        assert_eq!(status, 400);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid target_url"));
+        let (status, _) = call(&admin, "PATCH", "/config", r#"{"show_body": "yes"}"#).await;
+        assert_eq!(status, 400);
+
+        let patch = r#"{"target_url": "http://localhost:7000", "show_body": true}"#;
+        let (status, config) = call(&admin, "PATCH", "/config", patch).await;
+        assert_eq!(status, 200);
+        assert_eq!(config["target_url"], "http://localhost:7000");
+        assert_eq!(config["show_body"], true);
+        assert!(admin.config.read().unwrap().show_body);
+    }
+
+    #[tokio::test]
+    async fn starts_and_stops_recording() {
+        let admin = admin(Vec::new());
+        let (status, _) = call(&admin, "POST", "/recording/start", "").await;
+        assert_eq!(status, 400, "no directory to resume yet");
+
+        let root = TempDir::new("admin-recording");
+        let dir = root.join("requests").to_string_lossy().into_owned();
+        let start = json!({ "directory": dir }).to_string();
+        let (status, config) = call(&admin, "POST", "/recording/start", &start).await;
+        assert_eq!((status, &config["recording"]), (200, &json!(true)));
+        assert!(Path::new(&dir).is_dir());
+
+        let (_, config) = call(&admin, "POST", "/recording/stop", "").await;
+        assert_eq!(config["recording"], false);
+        let (_, config) = call(&admin, "POST", "/recording/start", "").await;
+        assert_eq!(config["save_request_directory"], dir.as_str());
+    }
+
+    #[tokio::test]
+    async fn fails_verification_with_417() {
+        let mock: Mock = serde_json::from_value(json!({
+            "method": "GET",
+            "path": "/once",
+            "times": 1,
+        }))
+        .unwrap();
+        let admin = admin(vec![mock]);
+        let (status, verification) = call(&admin, "GET", "/verify", "").await;
+        assert_eq!((status, &verification["ok"]), (417, &json!(false)));
+        assert_eq!(verification["unmet"].as_array().unwrap().len(), 1);
+
+        admin.mocks.find("GET", "/once");
+        let (status, verification) = call(&admin, "GET", "/verify", "").await;
+        assert_eq!((status, &verification["ok"]), (200, &json!(true)));
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        let response = warp::test::request()
+            .path("/metrics")
+            .reply(&routes(admin(Vec::new())))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; version=0.0.4"
+        );
+    }
+}
@@ -0,0 +1,495 @@
+use bytes::Bytes;
+use log::{info, warn};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use warp::http::header::{CONTENT_LENGTH, CONTENT_TYPE, ETAG};
+use warp::http::{HeaderMap, HeaderValue, Method};
+
+/// A body transform (loaded from the mock config file).
+/// Rules under `[[request_body]]` rewrite requests before they are forwarded,
+/// rules under `[[response_body]]` rewrite responses coming back from the target.
+/// For example, from TOML:
+///
+/// [[request_body]]
+/// method = "POST"
+/// content_type = "application/x-www-form-urlencoded"
+/// action = "form_set"
+/// field = "tenant_id"
+/// value = "test-tenant"
+///
+/// [[response_body]]
+/// path = "/v1/flags"
+/// action = "jsonpath_set"
+/// jsonpath = "$.flags.new_checkout"
+/// value = true
+///
+/// [[response_body]]
+/// path = "/v1/*"
+/// content_type = "text/html"
+/// action = "replace"
+/// find = "https://api.example.com"
+/// replacement = "http://localhost:6969"
+#[derive(Debug, Deserialize, Clone)]
+pub struct BodyRule {
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(flatten)]
+    pub action: BodyAction,
+}
+
+/// What a body rule does to the body.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum BodyAction {
+    /// Replaces every occurrence of `find` with `replacement`.
+    Replace { find: String, replacement: String },
+    /// Runs a regex replacement over the body.
+    RegexReplace {
+        #[serde(deserialize_with = "crate::header_rules::deserialize_regex")]
+        pattern: Regex,
+        replacement: String,
+    },
+    /// Applies a JSON Patch (RFC 6902).
+    JsonPatch { patch: json_patch::Patch },
+    /// Applies a JSON Merge Patch (RFC 7396).
+    MergePatch { patch: Value },
+    /// Sets every location the JSONPath points at to `value`.
+    JsonpathSet { jsonpath: JsonPath, value: Value },
+    /// Deletes every location the JSONPath points at.
+    JsonpathDelete { jsonpath: JsonPath },
+    /// Sets a field of a form-urlencoded body, adding it if missing.
+    FormSet { field: String, value: String },
+    /// Removes a field from a form-urlencoded body.
+    FormDelete { field: String },
+}
+
+impl BodyRule {
+    /// Whether the rule is scoped to this method and path.
+    pub fn matches_route(&self, method: &Method, path: &str) -> bool {
+        self.method
+            .as_ref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(method.as_str()))
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|p| crate::path_matches(p, path))
+    }
+
+    /// Whether the rule is scoped to this method, path and content type.
+    pub fn applies_to(&self, method: &Method, path: &str, content_type: Option<&str>) -> bool {
+        self.matches_route(method, path)
+            && self.content_type.as_ref().is_none_or(|expected| {
+                content_type.is_some_and(|actual| {
+                    actual
+                        .to_ascii_lowercase()
+                        .contains(&expected.to_ascii_lowercase())
+                })
+            })
+    }
+}
+
+/// Runs every rule scoped to the method, path and content type over the body,
+/// in order. Rules that cannot be applied are logged and skipped.
+pub fn apply(
+    rules: &[BodyRule],
+    method: &Method,
+    path: &str,
+    content_type: Option<&str>,
+    body: Bytes,
+) -> Bytes {
+    let mut body = body;
+    for rule in rules
+        .iter()
+        .filter(|r| r.applies_to(method, path, content_type))
+    {
+        match apply_action(&rule.action, &body) {
+            Ok(rewritten) => {
+                info!(
+                    "Rewrote body of {} {} ({} -> {} bytes)",
+                    method,
+                    path,
+                    body.len(),
+                    rewritten.len()
+                );
+                body = Bytes::from(rewritten);
+            }
+            Err(e) => warn!("Skipping body rule {:?}: {}", rule.action, e),
+        }
+    }
+    body
+}
+
+/// Runs the rules over a response body, by the response's content type.
+/// A rewritten body gets a matching Content-Length, and loses the upstream's
+/// ETag, which names the body the upstream sent.
+pub fn apply_to_response(
+    rules: &[BodyRule],
+    method: &Method,
+    path: &str,
+    headers: &mut HeaderMap,
+    body: Bytes,
+) -> Bytes {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let rewritten = apply(rules, method, path, content_type.as_deref(), body.clone());
+    if rewritten != body {
+        headers.remove(ETAG);
+        if headers.contains_key(CONTENT_LENGTH) {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(rewritten.len()));
+        }
+    }
+    rewritten
+}
+
+fn apply_action(action: &BodyAction, body: &[u8]) -> Result<Vec<u8>, String> {
+    match action {
+        BodyAction::Replace { find, replacement } => Ok(as_text(body)?
+            .replace(find.as_str(), replacement)
+            .into_bytes()),
+        BodyAction::RegexReplace {
+            pattern,
+            replacement,
+        } => Ok(pattern
+            .replace_all(as_text(body)?, replacement.as_str())
+            .into_owned()
+            .into_bytes()),
+        BodyAction::JsonPatch { patch } => {
+            let mut json = as_json(body)?;
+            json_patch::patch(&mut json, patch).map_err(|e| e.to_string())?;
+            serde_json::to_vec(&json).map_err(|e| e.to_string())
+        }
+        BodyAction::MergePatch { patch } => {
+            let mut json = as_json(body)?;
+            json_patch::merge(&mut json, patch);
+            serde_json::to_vec(&json).map_err(|e| e.to_string())
+        }
+        BodyAction::JsonpathSet { jsonpath, value } => {
+            let mut json = as_json(body)?;
+            jsonpath.apply(&mut json, Some(value));
+            serde_json::to_vec(&json).map_err(|e| e.to_string())
+        }
+        BodyAction::JsonpathDelete { jsonpath } => {
+            let mut json = as_json(body)?;
+            jsonpath.apply(&mut json, None);
+            serde_json::to_vec(&json).map_err(|e| e.to_string())
+        }
+        BodyAction::FormSet { field, value } => {
+            let mut pairs = as_form(body);
+            match pairs.iter_mut().find(|(k, _)| k == field) {
+                Some(pair) => pair.1 = value.clone(),
+                None => pairs.push((field.clone(), value.clone())),
+            }
+            Ok(to_form(&pairs))
+        }
+        BodyAction::FormDelete { field } => {
+            let mut pairs = as_form(body);
+            pairs.retain(|(k, _)| k != field);
+            Ok(to_form(&pairs))
+        }
+    }
+}
+
+fn as_form(body: &[u8]) -> Vec<(String, String)> {
+    url::form_urlencoded::parse(body).into_owned().collect()
+}
+
+fn to_form(pairs: &[(String, String)]) -> Vec<u8> {
+    url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(pairs)
+        .finish()
+        .into_bytes()
+}
+
+fn as_text(body: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(body).map_err(|_| "body is not valid UTF-8".to_string())
+}
+
+fn as_json(body: &[u8]) -> Result<Value, String> {
+    serde_json::from_slice(body).map_err(|e| format!("body is not valid JSON: {}", e))
+}
+
+/// A small JSONPath subset: `$`, `.key`, `['key']`, `[0]`, `[*]` and `.*`.
+#[derive(Debug, Clone)]
+pub struct JsonPath {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    Wildcard,
+}
+
+impl JsonPath {
+    pub fn parse(source: &str) -> Result<JsonPath, String> {
+        let rest = source
+            .trim()
+            .strip_prefix('$')
+            .ok_or_else(|| format!("JSONPath {:?} must start with '$'", source))?;
+        let chars: Vec<char> = rest.chars().collect();
+        let mut segments = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '.' => {
+                    let start = i + 1;
+                    i = start;
+                    while i < chars.len() && !matches!(chars[i], '.' | '[' | ']') {
+                        i += 1;
+                    }
+                    let key: String = chars[start..i].iter().collect();
+                    segments.push(match key.as_str() {
+                        "" => return Err(format!("empty key in JSONPath {:?}", source)),
+                        "*" => Segment::Wildcard,
+                        _ => Segment::Key(key),
+                    });
+                }
+                '[' => {
+                    let close = chars[i..]
+                        .iter()
+                        .position(|c| *c == ']')
+                        .map(|p| p + i)
+                        .ok_or_else(|| format!("unclosed '[' in JSONPath {:?}", source))?;
+                    let inner: String = chars[i + 1..close].iter().collect();
+                    let inner = inner.trim();
+                    segments.push(if inner == "*" {
+                        Segment::Wildcard
+                    } else if let Ok(index) = inner.parse::<usize>() {
+                        Segment::Index(index)
+                    } else {
+                        Segment::Key(inner.trim_matches(|c| c == '\'' || c == '"').to_string())
+                    });
+                    i = close + 1;
+                }
+                c => return Err(format!("unexpected {:?} in JSONPath {:?}", c, source)),
+            }
+        }
+        if segments.is_empty() {
+            return Err(format!("JSONPath {:?} must point below '$'", source));
+        }
+        Ok(JsonPath {
+            source: source.to_string(),
+            segments,
+        })
+    }
+
+    /// Sets every matched location to `value`, or deletes it when `value` is `None`.
+    /// Missing object keys are created when setting.
+    fn apply(&self, json: &mut Value, value: Option<&Value>) {
+        apply_segments(json, &self.segments, value);
+    }
+}
+
+fn apply_segments(json: &mut Value, segments: &[Segment], value: Option<&Value>) {
+    let (segment, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    if rest.is_empty() {
+        match (segment, json, value) {
+            (Segment::Key(key), Value::Object(map), Some(value)) => {
+                map.insert(key.clone(), value.clone());
+            }
+            (Segment::Key(key), Value::Object(map), None) => {
+                map.remove(key);
+            }
+            (Segment::Index(index), Value::Array(items), Some(value)) if *index < items.len() => {
+                items[*index] = value.clone();
+            }
+            (Segment::Index(index), Value::Array(items), None) if *index < items.len() => {
+                items.remove(*index);
+            }
+            (Segment::Wildcard, Value::Object(map), Some(value)) => {
+                map.values_mut().for_each(|v| *v = value.clone());
+            }
+            (Segment::Wildcard, Value::Array(items), Some(value)) => {
+                items.iter_mut().for_each(|v| *v = value.clone());
+            }
+            (Segment::Wildcard, Value::Object(map), None) => map.clear(),
+            (Segment::Wildcard, Value::Array(items), None) => items.clear(),
+            _ => {}
+        }
+        return;
+    }
+
+    match (segment, json) {
+        (Segment::Key(key), Value::Object(map)) => {
+            if value.is_some() && !map.contains_key(key) {
+                map.insert(key.clone(), Value::Object(Default::default()));
+            }
+            if let Some(child) = map.get_mut(key) {
+                apply_segments(child, rest, value);
+            }
+        }
+        (Segment::Index(index), Value::Array(items)) => {
+            if let Some(child) = items.get_mut(*index) {
+                apply_segments(child, rest, value);
+            }
+        }
+        (Segment::Wildcard, Value::Object(map)) => {
+            for child in map.values_mut() {
+                apply_segments(child, rest, value);
+            }
+        }
+        (Segment::Wildcard, Value::Array(items)) => {
+            for child in items.iter_mut() {
+                apply_segments(child, rest, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl<'de> Deserialize<'de> for JsonPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<JsonPath, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        JsonPath::parse(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn segments(source: &str) -> Vec<Segment> {
+        JsonPath::parse(source).unwrap().segments
+    }
+
+    #[test]
+    fn parses_dotted_and_bracketed_segments() {
+        use Segment::*;
+        assert_eq!(
+            segments("$.flags.new_checkout"),
+            vec![Key("flags".into()), Key("new_checkout".into())]
+        );
+        assert_eq!(
+            segments("$.items[0].id"),
+            vec![Key("items".into()), Index(0), Key("id".into())]
+        );
+        assert_eq!(
+            segments("$['a.b'][\"c\"]"),
+            vec![Key("a.b".into()), Key("c".into())]
+        );
+        assert_eq!(
+            segments("$.items[*].*"),
+            vec![Key("items".into()), Wildcard, Wildcard]
+        );
+        assert_eq!(segments(" $[ 2 ] "), vec![Index(2)]);
+    }
+
+    #[test]
+    fn rejects_malformed_paths() {
+        for source in ["flags", "$", "$.", "$..a", "$.a[0", "$a", "$.a]"] {
+            assert!(
+                JsonPath::parse(source).is_err(),
+                "{:?} should not parse",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn sets_existing_and_missing_keys() {
+        let mut body = json!({ "flags": { "old": true } });
+        JsonPath::parse("$.flags.new_checkout")
+            .unwrap()
+            .apply(&mut body, Some(&json!(false)));
+        JsonPath::parse("$.meta.region")
+            .unwrap()
+            .apply(&mut body, Some(&json!("eu")));
+        assert_eq!(
+            body,
+            json!({ "flags": { "old": true, "new_checkout": false }, "meta": { "region": "eu" } })
+        );
+    }
+
+    #[test]
+    fn sets_through_wildcards_and_indexes() {
+        let mut body = json!({ "items": [{ "id": 1 }, { "id": 2 }], "tags": ["a", "b"] });
+        JsonPath::parse("$.items[*].id")
+            .unwrap()
+            .apply(&mut body, Some(&json!(0)));
+        JsonPath::parse("$.tags[1]")
+            .unwrap()
+            .apply(&mut body, Some(&json!("z")));
+        // Out of range indexes and type mismatches are left alone.
+        JsonPath::parse("$.tags[5]")
+            .unwrap()
+            .apply(&mut body, Some(&json!("x")));
+        JsonPath::parse("$.tags.name")
+            .unwrap()
+            .apply(&mut body, Some(&json!("x")));
+        assert_eq!(
+            body,
+            json!({ "items": [{ "id": 0 }, { "id": 0 }], "tags": ["a", "z"] })
+        );
+    }
+
+    #[test]
+    fn deletes_keys_indexes_and_wildcards() {
+        let mut body = json!({ "a": 1, "b": [1, 2, 3], "c": { "x": 1, "y": 2 } });
+        JsonPath::parse("$.a").unwrap().apply(&mut body, None);
+        JsonPath::parse("$.b[0]").unwrap().apply(&mut body, None);
+        JsonPath::parse("$.c.*").unwrap().apply(&mut body, None);
+        // Deleting never creates anything.
+        JsonPath::parse("$.missing.key")
+            .unwrap()
+            .apply(&mut body, None);
+        assert_eq!(body, json!({ "b": [2, 3], "c": {} }));
+    }
+
+    #[test]
+    fn drops_the_etag_of_rewritten_responses() {
+        #[derive(Deserialize)]
+        struct File {
+            rules: Vec<BodyRule>,
+        }
+        let rules = toml::from_str::<File>(
+            r#"
+            [[rules]]
+            path = "/v1/*"
+            action = "replace"
+            find = "api.example.com"
+            replacement = "localhost:6969"
+            "#,
+        )
+        .unwrap()
+        .rules;
+        let rewrite = |path: &str| {
+            let mut headers = crate::test_support::headers(&[
+                ("content-type", "text/plain"),
+                ("content-length", "19"),
+                ("etag", "\"v1\""),
+            ]);
+            let body = Bytes::from_static(b"api.example.com/img");
+            let body = apply_to_response(&rules, &Method::GET, path, &mut headers, body);
+            (body, headers)
+        };
+
+        let (body, headers) = rewrite("/v1/page");
+        assert_eq!(body, "localhost:6969/img");
+        assert_eq!(headers["content-length"], "18");
+        assert!(!headers.contains_key("etag"));
+
+        let (body, headers) = rewrite("/v2/page");
+        assert_eq!(body, "api.example.com/img");
+        assert_eq!(headers["etag"], "\"v1\"");
+    }
+}
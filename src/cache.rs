@@ -0,0 +1,637 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use warp::http::{HeaderMap, Method};
+
+/// A per-route cache rule (loaded from the mock config file).
+/// For example, from TOML:
+///
+/// [[cache]]
+/// method = "GET"
+/// path = "/v1/slow/*"
+/// ttl = 300
+///
+/// A `ttl` of 0 disables caching for the matching routes.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CacheRule {
+    #[serde(default)]
+    pub method: Option<String>,
+    pub path: String,
+    pub ttl: u64,
+}
+
+/// A response stored in the cache, together with its validators and expiry.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CachedResponse {
+    pub key: String,
+    pub path: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "base64_body")]
+    pub body: Vec<u8>,
+    pub stored_at: i64,
+    pub expires_at: i64,
+    /// The request headers named by the response's `Vary`, with the values
+    /// they had; the entry only answers requests sending the same values.
+    #[serde(default)]
+    pub vary: Vec<(String, String)>,
+}
+
+impl CachedResponse {
+    /// Whether the entry can still be served without asking the upstream.
+    pub fn is_fresh(&self) -> bool {
+        chrono::Utc::now().timestamp() < self.expires_at
+    }
+
+    /// Returns the value of the given header (case-insensitive), if stored.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the entry was stored for a request with these `Vary` headers.
+    pub fn varies_from(&self, req_headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .any(|(name, value)| header_value(req_headers, name) != *value)
+    }
+
+    /// Whether the upstream gave us something to revalidate the entry with.
+    pub fn has_validators(&self) -> bool {
+        self.header("etag").is_some() || self.header("last-modified").is_some()
+    }
+}
+
+/// The result of looking up a request in the cache.
+pub enum Lookup {
+    /// The entry can be served as-is.
+    Fresh(CachedResponse),
+    /// The entry has expired, but can be revalidated with a conditional request.
+    Stale(CachedResponse),
+    /// Nothing usable is cached.
+    Miss,
+}
+
+/// In-memory (and optionally on-disk) cache of upstream responses.
+///
+/// Entries are keyed by method, path, query and the headers selected with
+/// `--cache-key-header`, and honor the upstream's `Vary`.
+pub struct ResponseCache {
+    default_ttl: u64,
+    rules: Vec<CacheRule>,
+    key_headers: Vec<String>,
+    dir: Option<PathBuf>,
+    entries: Mutex<HashMap<String, CachedResponse>>,
+}
+
+impl ResponseCache {
+    pub fn new(
+        default_ttl: u64,
+        rules: Vec<CacheRule>,
+        key_headers: Vec<String>,
+        dir: Option<String>,
+    ) -> ResponseCache {
+        let dir = dir.map(PathBuf::from);
+        let mut entries = HashMap::new();
+
+        // Load whatever a previous run left on disk.
+        if let Some(ref dir) = dir {
+            if let Err(e) = fs::create_dir_all(dir) {
+                error!("Failed to create cache directory {}: {}", dir.display(), e);
+            } else if let Ok(read_dir) = fs::read_dir(dir) {
+                for file in read_dir.flatten() {
+                    let path = file.path();
+                    if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                        continue;
+                    }
+                    match fs::read_to_string(&path)
+                        .map_err(|e| e.to_string())
+                        .and_then(|c| {
+                            serde_json::from_str::<CachedResponse>(&c).map_err(|e| e.to_string())
+                        }) {
+                        Ok(entry) => {
+                            // Files named by an older hash are renamed, so
+                            // purging finds them.
+                            let stable = dir.join(file_name(&entry.key));
+                            if path != stable {
+                                if let Err(e) = fs::rename(&path, &stable) {
+                                    warn!("Failed to rename cache file {}: {}", path.display(), e);
+                                }
+                            }
+                            entries.insert(entry.key.clone(), entry);
+                        }
+                        Err(e) => warn!("Ignoring cache file {}: {}", path.display(), e),
+                    }
+                }
+                info!(
+                    "Loaded {} cached response(s) from {}",
+                    entries.len(),
+                    dir.display()
+                );
+            }
+        }
+
+        ResponseCache {
+            default_ttl,
+            rules,
+            key_headers,
+            dir,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Returns the first cache rule matching the method and path.
+    fn rule_for(&self, method: &Method, path: &str) -> Option<&CacheRule> {
+        self.rules.iter().find(|r| {
+            r.method
+                .as_ref()
+                .is_none_or(|m| m.eq_ignore_ascii_case(method.as_str()))
+                && crate::path_matches(&r.path, path)
+        })
+    }
+
+    /// Builds the cache key for a request, or `None` if it must not be cached.
+    pub fn key_for(
+        &self,
+        method: &Method,
+        path: &str,
+        query: &str,
+        headers: &HeaderMap,
+    ) -> Option<String> {
+        if method != Method::GET && method != Method::HEAD {
+            return None;
+        }
+        if matches!(self.rule_for(method, path), Some(rule) if rule.ttl == 0) {
+            return None;
+        }
+
+        let mut key = format!("{} {}?{}", method, path, query);
+        for name in &self.key_headers {
+            let value = headers
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("");
+            key.push_str(&format!("|{}={}", name.to_lowercase(), value));
+        }
+        Some(key)
+    }
+
+    /// Looks up a key, dropping entries that expired and cannot be revalidated.
+    /// An entry stored for other values of its `Vary` headers is a miss.
+    pub fn lookup(&self, key: &str, req_headers: &HeaderMap) -> Lookup {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.varies_from(req_headers) => Lookup::Miss,
+            Some(entry) if entry.is_fresh() => Lookup::Fresh(entry.clone()),
+            Some(entry) if entry.has_validators() => Lookup::Stale(entry.clone()),
+            Some(_) => {
+                entries.remove(key);
+                self.remove_from_disk(key);
+                Lookup::Miss
+            }
+            None => Lookup::Miss,
+        }
+    }
+
+    /// Works out how long an upstream response may be cached, or `None` if
+    /// it must not be stored at all.
+    ///
+    /// Route rules win over the upstream's `Cache-Control: max-age`, which in
+    /// turn wins over `--cache-ttl`. Nothing is stored when either side sends
+    /// `no-store`.
+    ///
+    /// As a shared cache (RFC 9111, section 3.5), the proxy doesn't store
+    /// responses to requests carrying credentials unless the upstream marks
+    /// them `public`, `s-maxage` or `must-revalidate`, or the credentials are
+    /// part of the cache key.
+    pub fn ttl_for_response(
+        &self,
+        method: &Method,
+        path: &str,
+        req_headers: &HeaderMap,
+        status: u16,
+        resp_headers: &HeaderMap,
+    ) -> Option<u64> {
+        if !matches!(status, 200 | 203 | 204 | 300 | 301 | 404 | 410) {
+            return None;
+        }
+        // A client's `no-store` covers the response to its request too.
+        if cache_control(req_headers)
+            .iter()
+            .any(|(name, _)| name == "no-store")
+        {
+            return None;
+        }
+        let directives = cache_control(resp_headers);
+        if directives
+            .iter()
+            .any(|(name, _)| name == "no-store" || name == "private")
+        {
+            return None;
+        }
+        if vary(resp_headers).iter().any(|name| name == "*") {
+            return None;
+        }
+        let has_credentials = CREDENTIAL_HEADERS.iter().any(|name| {
+            req_headers.contains_key(*name)
+                && !self
+                    .key_headers
+                    .iter()
+                    .any(|k| k.eq_ignore_ascii_case(name))
+        });
+        let shareable = directives
+            .iter()
+            .any(|(name, _)| matches!(name.as_str(), "public" | "s-maxage" | "must-revalidate"));
+        if has_credentials && !shareable {
+            return None;
+        }
+        if let Some(rule) = self.rule_for(method, path) {
+            return Some(rule.ttl);
+        }
+        let max_age = directives
+            .iter()
+            .find(|(name, _)| name == "s-maxage")
+            .or_else(|| directives.iter().find(|(name, _)| name == "max-age"))
+            .and_then(|(_, value)| value.as_ref())
+            .and_then(|value| value.parse::<u64>().ok());
+        if let Some(max_age) = max_age {
+            return Some(max_age);
+        }
+        if directives.iter().any(|(name, _)| name == "no-cache") {
+            return Some(0);
+        }
+        Some(self.default_ttl)
+    }
+
+    /// Stores an upstream response to a request with `req_headers`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn store(
+        &self,
+        key: &str,
+        path: &str,
+        req_headers: &HeaderMap,
+        status: u16,
+        resp_headers: &HeaderMap,
+        body: &[u8],
+        ttl: u64,
+    ) {
+        let now = chrono::Utc::now().timestamp();
+        let vary = vary(resp_headers)
+            .into_iter()
+            .map(|name| {
+                let value = header_value(req_headers, &name);
+                (name, value)
+            })
+            .collect();
+        let entry = CachedResponse {
+            key: key.to_string(),
+            path: path.to_string(),
+            status,
+            headers: header_pairs(resp_headers),
+            body: body.to_vec(),
+            stored_at: now,
+            expires_at: now + ttl as i64,
+            vary,
+        };
+        if entry.expires_at <= now && !entry.has_validators() {
+            return;
+        }
+        self.write_to_disk(&entry);
+        self.entries.lock().unwrap().insert(key.to_string(), entry);
+    }
+
+    /// Marks an entry fresh again after the upstream answered `304 Not Modified`,
+    /// merging in any updated headers. Returns the refreshed entry.
+    pub fn refresh(&self, key: &str, resp_headers: &HeaderMap, ttl: u64) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        for (name, value) in header_pairs(resp_headers) {
+            entry
+                .headers
+                .retain(|(k, _)| !k.eq_ignore_ascii_case(&name));
+            entry.headers.push((name, value));
+        }
+        entry.expires_at = chrono::Utc::now().timestamp() + ttl as i64;
+        let entry = entry.clone();
+        drop(entries);
+        self.write_to_disk(&entry);
+        Some(entry)
+    }
+
+    /// Removes every entry whose path matches the glob pattern.
+    /// Returns the number of purged entries.
+    pub fn purge(&self, pattern: &str) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let keys: Vec<String> = entries
+            .values()
+            .filter(|e| pattern == "*" || crate::path_matches(pattern, &e.path))
+            .map(|e| e.key.clone())
+            .collect();
+        for key in &keys {
+            entries.remove(key);
+            self.remove_from_disk(key);
+        }
+        keys.len()
+    }
+
+    fn file_for(&self, key: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(file_name(key)))
+    }
+
+    fn write_to_disk(&self, entry: &CachedResponse) {
+        if let Some(path) = self.file_for(&entry.key) {
+            let result = serde_json::to_string(entry)
+                .map_err(|e| e.to_string())
+                .and_then(|json| fs::write(&path, json).map_err(|e| e.to_string()));
+            if let Err(e) = result {
+                error!("Failed to write cache file {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn remove_from_disk(&self, key: &str) {
+        if let Some(path) = self.file_for(key) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Request headers carrying credentials, whose responses must not be shared.
+const CREDENTIAL_HEADERS: &[&str] = &["authorization", "cookie"];
+
+/// The on-disk file name of an entry: a hash of its key that stays the same
+/// across runs and Rust releases.
+fn file_name(key: &str) -> String {
+    format!("{:016x}.json", stable_hash(key))
+}
+
+/// 64-bit FNV-1a, for hashes that must not change between runs or Rust
+/// releases (unlike `DefaultHasher`'s).
+pub fn stable_hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// The lowercase header names listed in `Vary`.
+fn vary(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(warp::http::header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Every value of a request header, joined as one.
+fn header_value(headers: &HeaderMap, name: &str) -> String {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Parses `Cache-Control` into lowercase `(directive, value)` pairs.
+pub fn cache_control(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(warp::http::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_lowercase(), None),
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.to_string(), v.to_string())))
+        .collect()
+}
+
+/// Stores cached bodies as base64 strings in the on-disk JSON files.
+mod base64_body {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(body: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(body))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::headers;
+
+    fn cache() -> ResponseCache {
+        ResponseCache::new(30, Vec::new(), Vec::new(), None)
+    }
+
+    fn ttl(
+        cache: &ResponseCache,
+        req: &[(&'static str, &str)],
+        resp: &[(&'static str, &str)],
+    ) -> Option<u64> {
+        cache.ttl_for_response(
+            &Method::GET,
+            "/v1/items",
+            &headers(req),
+            200,
+            &headers(resp),
+        )
+    }
+
+    fn store(
+        cache: &ResponseCache,
+        key: &str,
+        req: &[(&'static str, &str)],
+        resp: &[(&'static str, &str)],
+        ttl: u64,
+    ) {
+        cache.store(
+            key,
+            "/v1/items",
+            &headers(req),
+            200,
+            &headers(resp),
+            b"{}",
+            ttl,
+        );
+    }
+
+    #[test]
+    fn prefers_s_maxage_over_max_age() {
+        let cache = cache();
+        assert_eq!(
+            ttl(&cache, &[], &[("cache-control", "max-age=60, s-maxage=10")]),
+            Some(10)
+        );
+        assert_eq!(
+            ttl(&cache, &[], &[("cache-control", "max-age=60")]),
+            Some(60)
+        );
+        assert_eq!(ttl(&cache, &[], &[]), Some(30));
+    }
+
+    #[test]
+    fn honors_no_store_private_and_no_cache() {
+        let cache = cache();
+        assert_eq!(ttl(&cache, &[], &[("cache-control", "no-store")]), None);
+        assert_eq!(
+            ttl(
+                &cache,
+                &[("cache-control", "no-store")],
+                &[("cache-control", "max-age=60")]
+            ),
+            None
+        );
+        assert_eq!(
+            ttl(&cache, &[], &[("cache-control", "private, max-age=60")]),
+            None
+        );
+        assert_eq!(ttl(&cache, &[], &[("cache-control", "no-cache")]), Some(0));
+    }
+
+    #[test]
+    fn keeps_credentialed_responses_private() {
+        let cache = cache();
+        let auth = [("authorization", "Bearer token")];
+        assert_eq!(ttl(&cache, &auth, &[("cache-control", "max-age=60")]), None);
+        assert_eq!(ttl(&cache, &[("cookie", "session=1")], &[]), None);
+        assert_eq!(
+            ttl(&cache, &auth, &[("cache-control", "public, max-age=60")]),
+            Some(60)
+        );
+        assert_eq!(
+            ttl(&cache, &auth, &[("cache-control", "s-maxage=5")]),
+            Some(5)
+        );
+
+        // Credentials that are part of the key don't leak between users.
+        let keyed = ResponseCache::new(30, Vec::new(), vec!["Authorization".to_string()], None);
+        assert_eq!(ttl(&keyed, &auth, &[]), Some(30));
+    }
+
+    #[test]
+    fn does_not_store_vary_star() {
+        assert_eq!(ttl(&cache(), &[], &[("vary", "Accept, *")]), None);
+    }
+
+    #[test]
+    fn keys_on_method_query_and_key_headers() {
+        let cache = ResponseCache::new(30, Vec::new(), vec!["X-Tenant".to_string()], None);
+        let key = |method: &Method, query: &str, req: &[(&'static str, &str)]| {
+            cache.key_for(method, "/v1/items", query, &headers(req))
+        };
+        assert_eq!(
+            key(&Method::GET, "page=2", &[("x-tenant", "acme")]).as_deref(),
+            Some("GET /v1/items?page=2|x-tenant=acme")
+        );
+        assert_ne!(
+            key(&Method::GET, "", &[("x-tenant", "acme")]),
+            key(&Method::GET, "", &[("x-tenant", "other")])
+        );
+        assert_eq!(key(&Method::POST, "", &[]), None);
+    }
+
+    #[test]
+    fn misses_entries_stored_for_other_vary_values() {
+        let cache = cache();
+        let vary = [("vary", "Accept-Language"), ("etag", "\"v1\"")];
+        store(&cache, "k", &[("accept-language", "en")], &vary, 60);
+        assert!(matches!(
+            cache.lookup("k", &headers(&[("accept-language", "en")])),
+            Lookup::Fresh(_)
+        ));
+        assert!(matches!(
+            cache.lookup("k", &headers(&[("accept-language", "fr")])),
+            Lookup::Miss
+        ));
+        assert!(matches!(cache.lookup("k", &HeaderMap::new()), Lookup::Miss));
+    }
+
+    #[test]
+    fn goes_from_fresh_to_stale_to_miss() {
+        let cache = cache();
+        let expire = |key: &str| {
+            cache
+                .entries
+                .lock()
+                .unwrap()
+                .get_mut(key)
+                .unwrap()
+                .expires_at -= 120;
+        };
+        store(&cache, "validated", &[], &[("etag", "\"v1\"")], 60);
+        store(&cache, "plain", &[], &[], 60);
+        assert!(matches!(
+            cache.lookup("validated", &HeaderMap::new()),
+            Lookup::Fresh(_)
+        ));
+        assert!(matches!(
+            cache.lookup("plain", &HeaderMap::new()),
+            Lookup::Fresh(_)
+        ));
+
+        expire("validated");
+        expire("plain");
+        assert!(matches!(
+            cache.lookup("validated", &HeaderMap::new()),
+            Lookup::Stale(_)
+        ));
+        // Without validators an expired entry is useless, and dropped.
+        assert!(matches!(
+            cache.lookup("plain", &HeaderMap::new()),
+            Lookup::Miss
+        ));
+        assert!(!cache.entries.lock().unwrap().contains_key("plain"));
+
+        let refreshed = cache
+            .refresh("validated", &headers(&[("etag", "\"v2\"")]), 60)
+            .unwrap();
+        assert_eq!(refreshed.header("ETag"), Some("\"v2\""));
+        assert!(matches!(
+            cache.lookup("validated", &HeaderMap::new()),
+            Lookup::Fresh(_)
+        ));
+    }
+
+    #[test]
+    fn hashes_keys_stably() {
+        // Fixed FNV-1a values, so cache files and sticky targets survive upgrades.
+        assert_eq!(stable_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(stable_hash("a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(file_name("a"), "af63dc4c8601ec8c.json");
+    }
+
+    #[test]
+    fn refresh_after_purge_returns_none() {
+        let cache = cache();
+        store(&cache, "k", &[], &[("etag", "\"v1\"")], 0);
+        assert_eq!(cache.purge("/v1/*"), 1);
+        assert!(cache.refresh("k", &HeaderMap::new(), 60).is_none());
+    }
+}
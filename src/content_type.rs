@@ -0,0 +1,132 @@
+/// Guesses the `Content-Type` of a mock body, first from the extension of the
+/// file it was loaded from, then from its first bytes.
+///
+/// Returns `None` for empty bodies, which need no content type at all.
+pub fn sniff(file_name: Option<&str>, body: &[u8]) -> Option<String> {
+    if body.is_empty() {
+        return None;
+    }
+
+    if let Some(mime) = file_name.and_then(|f| mime_guess::from_path(f).first()) {
+        return Some(with_charset(mime.essence_str()));
+    }
+
+    let magic: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\x00\x00\x01\x00", "image/x-icon"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+    ];
+    if let Some((_, mime)) = magic.iter().find(|(prefix, _)| body.starts_with(prefix)) {
+        return Some(mime.to_string());
+    }
+    if body.len() >= 12 && &body[..4] == b"RIFF" && &body[8..12] == b"WEBP" {
+        return Some("image/webp".to_string());
+    }
+
+    let text = match std::str::from_utf8(body) {
+        Ok(text) => text.trim_start(),
+        Err(_) => return Some("application/octet-stream".to_string()),
+    };
+    let lowercase = text
+        .chars()
+        .take(64)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    let mime = if (text.starts_with('{') || text.starts_with('['))
+        && serde_json::from_str::<serde_json::Value>(text).is_ok()
+    {
+        "application/json"
+    } else if lowercase.starts_with("<!doctype html") || lowercase.starts_with("<html") {
+        "text/html"
+    } else if lowercase.starts_with("<svg") {
+        "image/svg+xml"
+    } else if lowercase.starts_with("<?xml") {
+        "application/xml"
+    } else {
+        "text/plain"
+    };
+    Some(with_charset(mime))
+}
+
+/// Adds `charset=utf-8` to textual types.
+fn with_charset(mime: &str) -> String {
+    if mime.starts_with("text/") {
+        format!("{}; charset=utf-8", mime)
+    } else {
+        mime.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_bodies() {
+        let cases: &[(Option<&str>, &[u8], Option<&str>)] = &[
+            // The extension wins over the bytes.
+            (Some("logo.svg"), b"{\"a\": 1}", Some("image/svg+xml")),
+            (
+                Some("notes.txt"),
+                b"<html></html>",
+                Some("text/plain; charset=utf-8"),
+            ),
+            // Unknown extensions fall back to the bytes.
+            (Some("body.unknownext"), b"[1, 2]", Some("application/json")),
+            (None, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", Some("image/png")),
+            (None, b"GIF89a\x01\0\x01\0", Some("image/gif")),
+            (None, b"GIF87a\x01\0\x01\0", Some("image/gif")),
+            (None, b"%PDF-1.7\n", Some("application/pdf")),
+            (None, b"PK\x03\x04\x14\0", Some("application/zip")),
+            (None, b"RIFF\0\0\0\0WEBPVP8 ", Some("image/webp")),
+            (None, b"  {\"id\": 1}", Some("application/json")),
+            (None, b"[{\"id\": 1}]\n", Some("application/json")),
+            // Looks like JSON but isn't.
+            (None, b"{not json", Some("text/plain; charset=utf-8")),
+            (
+                None,
+                b"<!DOCTYPE html><html></html>",
+                Some("text/html; charset=utf-8"),
+            ),
+            (
+                None,
+                b"\n<HTML><body></body></HTML>",
+                Some("text/html; charset=utf-8"),
+            ),
+            (
+                None,
+                b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>",
+                Some("image/svg+xml"),
+            ),
+            (
+                None,
+                b"<?xml version=\"1.0\"?><a/>",
+                Some("application/xml"),
+            ),
+            (None, b"plain words", Some("text/plain; charset=utf-8")),
+            (
+                None,
+                b"\xff\xfe\x00garbage\x80",
+                Some("application/octet-stream"),
+            ),
+            (None, b"", None),
+            (Some("empty.json"), b"", None),
+        ];
+        for (file_name, body, expected) in cases {
+            assert_eq!(
+                sniff(*file_name, body).as_deref(),
+                *expected,
+                "{:?} {:?}",
+                file_name,
+                String::from_utf8_lossy(body)
+            );
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use log::info;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use warp::http::header::{self, HeaderName};
+use warp::http::{HeaderMap, HeaderValue, Method};
+
+/// A CORS policy for a set of routes (loaded from the mock config file).
+/// For example, from TOML:
+///
+/// [[cors]]
+/// path = "/v1/*"
+/// origins = ["http://localhost:3000"]
+/// origin_patterns = ["^https://.*\\.example\\.com$"]
+/// allow_credentials = true
+/// expose_headers = ["X-Request-Id"]
+/// max_age = 600
+#[derive(Debug, Deserialize, Clone)]
+pub struct CorsRule {
+    /// Routes the policy applies to; all routes if missing.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Allowed origins. `"*"` allows any origin.
+    #[serde(default = "default_origins")]
+    pub origins: Vec<String>,
+    /// Regexes an origin may match instead of being listed in `origins`.
+    #[serde(default, deserialize_with = "deserialize_regexes")]
+    pub origin_patterns: Vec<Regex>,
+    /// Methods answered in preflights.
+    #[serde(default = "default_methods")]
+    pub methods: Vec<String>,
+    /// Request headers answered in preflights; the requested ones are reflected if empty.
+    #[serde(default)]
+    pub headers: Vec<String>,
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    #[serde(default)]
+    pub max_age: Option<u64>,
+}
+
+fn default_origins() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn default_methods() -> Vec<String> {
+    ["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"]
+        .iter()
+        .map(|m| m.to_string())
+        .collect()
+}
+
+impl Default for CorsRule {
+    fn default() -> CorsRule {
+        CorsRule {
+            path: None,
+            origins: default_origins(),
+            origin_patterns: Vec::new(),
+            methods: default_methods(),
+            headers: Vec::new(),
+            expose_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl CorsRule {
+    fn allows_any_origin(&self) -> bool {
+        self.origins.iter().any(|o| o == "*")
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.allows_any_origin()
+            || self.origins.iter().any(|o| o.eq_ignore_ascii_case(origin))
+            || self.origin_patterns.iter().any(|p| p.is_match(origin))
+    }
+
+    /// Whether every request gets `Access-Control-Allow-Origin: *`.
+    fn answers_any_origin(&self) -> bool {
+        self.allows_any_origin() && !self.allow_credentials
+    }
+
+    /// The `Access-Control-Allow-Origin` value for a request, or `None` if the
+    /// origin is not allowed. Credentialed policies always echo the origin,
+    /// since browsers reject `*` together with credentials.
+    fn allow_origin(&self, origin: Option<&str>) -> Option<String> {
+        match origin {
+            Some(origin) if self.allows_origin(origin) => {
+                if self.answers_any_origin() {
+                    Some("*".to_string())
+                } else {
+                    Some(origin.to_string())
+                }
+            }
+            Some(_) => None,
+            None if self.answers_any_origin() => Some("*".to_string()),
+            None => None,
+        }
+    }
+}
+
+/// CORS handling for the proxy: `[[cors]]` rules from the mock config file,
+/// with `--add-cors-headers` as a permissive fallback for the other routes.
+#[derive(Debug, Default)]
+pub struct Cors {
+    pub rules: Vec<CorsRule>,
+    pub fallback: Option<CorsRule>,
+}
+
+impl Cors {
+    pub fn new(rules: Vec<CorsRule>, add_cors_headers: bool) -> Cors {
+        Cors {
+            rules,
+            fallback: add_cors_headers.then(CorsRule::default),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty() || self.fallback.is_some()
+    }
+
+    fn policy_for(&self, path: &str) -> Option<&CorsRule> {
+        self.rules
+            .iter()
+            .find(|r| r.path.as_ref().is_none_or(|p| crate::path_matches(p, path)))
+            .or(self.fallback.as_ref())
+    }
+
+    /// Answers a CORS preflight without going upstream. Returns `None` if the
+    /// request isn't a preflight or no policy covers its path.
+    pub fn preflight(
+        &self,
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+    ) -> Option<warp::http::Response<bytes::Bytes>> {
+        if method != Method::OPTIONS
+            || !headers.contains_key(header::ORIGIN)
+            || !headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return None;
+        }
+        let policy = self.policy_for(path)?;
+        let origin = headers.get(header::ORIGIN).and_then(|v| v.to_str().ok());
+
+        let Some(allow_origin) = policy.allow_origin(origin) else {
+            info!(
+                "Rejected CORS preflight for {} from origin {}",
+                path,
+                origin.unwrap_or("")
+            );
+            return Some(
+                warp::http::Response::builder()
+                    .status(warp::http::StatusCode::FORBIDDEN)
+                    .header(header::VARY, "Origin")
+                    .body(bytes::Bytes::from("CORS origin not allowed"))
+                    .expect("failed to build preflight response"),
+            );
+        };
+        info!(
+            "Answered CORS preflight for {} from origin {}",
+            path,
+            origin.unwrap_or("")
+        );
+
+        let allow_headers = if policy.headers.is_empty() {
+            headers
+                .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("")
+                .to_string()
+        } else {
+            policy.headers.join(", ")
+        };
+
+        let mut builder = warp::http::Response::builder()
+            .status(warp::http::StatusCode::NO_CONTENT)
+            .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin)
+            .header(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                policy.methods.join(", "),
+            )
+            .header(
+                header::VARY,
+                "Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+            );
+        if !allow_headers.is_empty() {
+            builder = builder.header(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+        if policy.allow_credentials {
+            builder = builder.header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+        if let Some(max_age) = policy.max_age {
+            builder = builder.header(header::ACCESS_CONTROL_MAX_AGE, max_age.to_string());
+        }
+        Some(
+            builder
+                .body(bytes::Bytes::new())
+                .expect("failed to build preflight response"),
+        )
+    }
+
+    /// Adds the CORS headers for an actual (non-preflight) response.
+    pub fn apply(&self, path: &str, request_headers: &HeaderMap, resp_headers: &mut HeaderMap) {
+        let Some(policy) = self.policy_for(path) else {
+            return;
+        };
+        let origin = request_headers
+            .get(header::ORIGIN)
+            .and_then(|v| v.to_str().ok());
+        // Unless every origin gets `*`, the answer depends on the origin, even
+        // when it's left without CORS headers.
+        if !policy.answers_any_origin() {
+            resp_headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        let Some(allow_origin) = policy.allow_origin(origin) else {
+            return;
+        };
+
+        insert(
+            resp_headers,
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            &allow_origin,
+        );
+        if policy.allow_credentials {
+            insert(
+                resp_headers,
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                "true",
+            );
+        }
+        if !policy.expose_headers.is_empty() {
+            insert(
+                resp_headers,
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                &policy.expose_headers.join(", "),
+            );
+        }
+    }
+}
+
+fn insert(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+fn deserialize_regexes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Regex>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|p| Regex::new(p).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::headers;
+
+    const APP: &str = "http://localhost:3000";
+
+    fn listed(allow_credentials: bool) -> CorsRule {
+        CorsRule {
+            path: Some("/v1/*".to_string()),
+            origins: vec![APP.to_string()],
+            origin_patterns: vec![Regex::new(r"^https://.*\.example\.com$").unwrap()],
+            allow_credentials,
+            ..CorsRule::default()
+        }
+    }
+
+    fn value(headers: &HeaderMap, name: HeaderName) -> Option<&str> {
+        headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    #[test]
+    fn answers_star_only_without_credentials() {
+        let open = CorsRule::default();
+        assert_eq!(open.allow_origin(Some(APP)).as_deref(), Some("*"));
+        assert_eq!(open.allow_origin(None).as_deref(), Some("*"));
+
+        // Browsers reject `*` with credentials, so the origin is echoed.
+        let credentialed = CorsRule {
+            allow_credentials: true,
+            ..CorsRule::default()
+        };
+        assert_eq!(credentialed.allow_origin(Some(APP)).as_deref(), Some(APP));
+        assert_eq!(credentialed.allow_origin(None), None);
+    }
+
+    #[test]
+    fn echoes_listed_and_matching_origins_only() {
+        let rule = listed(false);
+        assert_eq!(rule.allow_origin(Some(APP)).as_deref(), Some(APP));
+        assert_eq!(
+            rule.allow_origin(Some("https://app.example.com"))
+                .as_deref(),
+            Some("https://app.example.com")
+        );
+        assert_eq!(rule.allow_origin(Some("https://example.com.evil.io")), None);
+        assert_eq!(rule.allow_origin(Some("http://localhost:3001")), None);
+        assert_eq!(rule.allow_origin(None), None);
+    }
+
+    #[test]
+    fn answers_allowed_preflights() {
+        let cors = Cors::new(vec![listed(true)], false);
+        let request = headers(&[
+            (header::ORIGIN, APP),
+            (header::ACCESS_CONTROL_REQUEST_METHOD, "PUT"),
+            (header::ACCESS_CONTROL_REQUEST_HEADERS, "x-token"),
+        ]);
+        let response = cors
+            .preflight(&Method::OPTIONS, "/v1/users", &request)
+            .unwrap();
+        assert_eq!(response.status(), warp::http::StatusCode::NO_CONTENT);
+        let h = response.headers();
+        assert_eq!(value(h, header::ACCESS_CONTROL_ALLOW_ORIGIN), Some(APP));
+        assert_eq!(
+            value(h, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            Some("true")
+        );
+        assert_eq!(
+            value(h, header::ACCESS_CONTROL_ALLOW_HEADERS),
+            Some("x-token")
+        );
+        assert!(value(h, header::VARY).unwrap().starts_with("Origin"));
+    }
+
+    #[test]
+    fn rejects_disallowed_preflights() {
+        let cors = Cors::new(vec![listed(true)], true);
+        let request = headers(&[
+            (header::ORIGIN, "https://evil.io"),
+            (header::ACCESS_CONTROL_REQUEST_METHOD, "GET"),
+        ]);
+        let response = cors
+            .preflight(&Method::OPTIONS, "/v1/users", &request)
+            .unwrap();
+        assert_eq!(response.status(), warp::http::StatusCode::FORBIDDEN);
+        assert_eq!(value(response.headers(), header::VARY), Some("Origin"));
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // Only OPTIONS requests with an origin and a requested method are preflights.
+        let plain = headers(&[(header::ORIGIN, APP)]);
+        assert!(cors
+            .preflight(&Method::OPTIONS, "/v1/users", &plain)
+            .is_none());
+        assert!(cors
+            .preflight(&Method::GET, "/v1/users", &request)
+            .is_none());
+    }
+
+    #[test]
+    fn varies_on_origin_unless_answering_star() {
+        let cors = Cors::new(vec![listed(false)], true);
+        let apply = |path: &str, origin: &str| {
+            let mut resp = HeaderMap::new();
+            cors.apply(path, &headers(&[(header::ORIGIN, origin)]), &mut resp);
+            resp
+        };
+
+        let allowed = apply("/v1/users", APP);
+        assert_eq!(
+            value(&allowed, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some(APP)
+        );
+        assert_eq!(value(&allowed, header::VARY), Some("Origin"));
+
+        let rejected = apply("/v1/users", "https://evil.io");
+        assert!(!rejected.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(value(&rejected, header::VARY), Some("Origin"));
+
+        // Routes outside the rules fall back to `--add-cors-headers`.
+        let fallback = apply("/v2/users", "https://evil.io");
+        assert_eq!(
+            value(&fallback, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("*")
+        );
+        assert!(!fallback.contains_key(header::VARY));
+    }
+}
@@ -0,0 +1,212 @@
+use crate::mock::{MockSource, MockStore, Unmatched};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+
+/// Which mocks a run used, and which requests no mock answered.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub generated_at: DateTime<Utc>,
+    pub mocks_total: usize,
+    pub mocks_used: usize,
+    pub mocks: Vec<MockCoverage>,
+    pub unmocked: Vec<Unmatched>,
+}
+
+/// How often one mock was hit.
+#[derive(Debug, Serialize)]
+pub struct MockCoverage {
+    pub id: u64,
+    pub method: String,
+    pub path: String,
+    pub source: MockSource,
+    pub hits: u64,
+    /// The expectation (`times`/`at_least`) the hits don't meet, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unmet: Option<String>,
+}
+
+impl Report {
+    pub fn new(store: &MockStore) -> Report {
+        let mocks: Vec<MockCoverage> = store
+            .list()
+            .into_iter()
+            .map(|m| MockCoverage {
+                unmet: m.unmet_expectation(),
+                id: m.id,
+                method: m.method,
+                path: m.path,
+                source: m.source,
+                hits: m.hits.get(),
+            })
+            .collect();
+        Report {
+            generated_at: Utc::now(),
+            mocks_total: mocks.len(),
+            mocks_used: mocks.iter().filter(|m| m.hits > 0).count(),
+            mocks,
+            unmocked: store.verify().unmatched,
+        }
+    }
+
+    /// One line for the log.
+    pub fn summary(&self) -> String {
+        format!(
+            "Mock coverage: {} of {} mock(s) used, {} unmocked endpoint(s)",
+            self.mocks_used,
+            self.mocks_total,
+            self.unmocked.len()
+        )
+    }
+
+    /// The report as plain text.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "{}", self.summary());
+        let _ = writeln!(text, "Generated at {}", self.generated_at.to_rfc3339());
+
+        let _ = writeln!(text, "\nMocks (hits, mock, source):");
+        for mock in &self.mocks {
+            let mut notes = Vec::new();
+            if mock.hits == 0 {
+                notes.push("never used".to_string());
+            }
+            notes.extend(mock.unmet.clone());
+            let _ = writeln!(
+                text,
+                "  {:>6}  {} {}  ({}){}",
+                mock.hits,
+                mock.method,
+                mock.path,
+                source_name(&mock.source),
+                if notes.is_empty() {
+                    String::new()
+                } else {
+                    format!("  <- {}", notes.join(", "))
+                }
+            );
+        }
+
+        let _ = writeln!(text, "\nUnmocked endpoints (requests, endpoint):");
+        if self.unmocked.is_empty() {
+            let _ = writeln!(text, "  none");
+        }
+        for unmatched in &self.unmocked {
+            let _ = writeln!(
+                text,
+                "  {:>6}  {} {}",
+                unmatched.count, unmatched.method, unmatched.path
+            );
+        }
+        text
+    }
+
+    /// Writes the report to `<base>.txt` and `<base>.json`. The extensions
+    /// are appended, so a dotted base like `coverage.v2` is kept whole.
+    pub fn write(&self, base: &str) {
+        let text_path = PathBuf::from(format!("{}.txt", base));
+        let json_path = PathBuf::from(format!("{}.json", base));
+        let json = serde_json::to_string_pretty(self).expect("coverage report serializes");
+        for (path, contents) in [(&text_path, self.text()), (&json_path, json)] {
+            match fs::write(path, contents) {
+                Ok(()) => info!("Wrote mock coverage report to {}", path.display()),
+                Err(e) => error!(
+                    "Failed to write mock coverage report to {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+fn source_name(source: &MockSource) -> &'static str {
+    match source {
+        MockSource::Config => "config",
+        MockSource::Directory => "directory",
+        MockSource::Admin => "admin",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Mock;
+    use crate::test_support::TempDir;
+
+    fn store() -> MockStore {
+        let mock = |path: &str, times: Option<u64>| -> Mock {
+            serde_json::from_value(serde_json::json!({
+                "method": "GET",
+                "path": path,
+                "times": times,
+            }))
+            .unwrap()
+        };
+        MockStore::new(vec![
+            mock("/used", None),
+            mock("/unused", None),
+            mock("/twice", Some(2)),
+        ])
+    }
+
+    #[test]
+    fn counts_used_mocks_and_notes_unmet_ones() {
+        let store = store();
+        store.find("GET", "/used");
+        store.find("GET", "/twice");
+        store.note_unmatched("POST", "/v1/orders");
+        store.note_unmatched("POST", "/v1/orders");
+
+        let report = Report::new(&store);
+        assert_eq!((report.mocks_used, report.mocks_total), (2, 3));
+        assert_eq!(report.unmocked.len(), 1);
+        let text = report.text();
+        assert!(
+            text.starts_with("Mock coverage: 2 of 3 mock(s) used, 1 unmocked endpoint(s)\n"),
+            "{}",
+            text
+        );
+        let line = |path: &str| {
+            text.lines()
+                .find(|l| l.contains(&format!("GET {}  ", path)))
+                .unwrap()
+                .to_string()
+        };
+        assert!(!line("/used").contains("<-"), "{}", text);
+        assert!(
+            line("/unused").ends_with("(config)  <- never used"),
+            "{}",
+            text
+        );
+        assert!(
+            line("/twice").ends_with("(config)  <- expected 2 hit(s)"),
+            "{}",
+            text
+        );
+        assert!(text.contains("\n       2  POST /v1/orders\n"), "{}", text);
+    }
+
+    #[test]
+    fn lists_none_without_unmocked_endpoints() {
+        let report = Report::new(&MockStore::default());
+        assert_eq!((report.mocks_used, report.mocks_total), (0, 0));
+        assert!(report
+            .text()
+            .ends_with("Unmocked endpoints (requests, endpoint):\n  none\n"));
+    }
+
+    #[test]
+    fn appends_the_extensions_to_the_base_path() {
+        let dir = TempDir::new("coverage");
+        let base = dir.join("coverage.v2");
+        Report::new(&store()).write(&base.to_string_lossy());
+        assert!(dir.join("coverage.v2.txt").is_file());
+        let json = fs::read_to_string(dir.join("coverage.v2.json")).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["mocks_total"], 3);
+    }
+}
@@ -0,0 +1,266 @@
+use crate::access_log::{self, AccessLog};
+use crate::journal::{self, Entry, Journal};
+use crate::{logging, metrics};
+use bytes::Bytes;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{info, log_enabled, Level};
+use serde::Serialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use warp::http::{HeaderMap, Method, Response, Version};
+
+/// How a request was answered.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Outcome {
+    /// A mock answered; `mock_id` is its id in the admin API.
+    Mock { mock_id: u64, mock_path: String },
+    /// A file from a `--static-dir` answered.
+    Static,
+    /// The proxy answered a CORS preflight itself.
+    Preflight,
+    /// A `PURGE` request cleared cached responses.
+    Purge,
+    /// The request went upstream (or was answered from the response cache).
+    Forwarded {
+        url: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache: Option<String>,
+        /// How long the upstream took, unless the cache answered.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        upstream_ms: Option<f64>,
+    },
+    /// The request was meant to go upstream, but the upstream couldn't be reached.
+    UpstreamError { url: String },
+}
+
+impl Outcome {
+    /// The `type` the outcome is serialized with.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Outcome::Mock { .. } => "mock",
+            Outcome::Static => "static",
+            Outcome::Preflight => "preflight",
+            Outcome::Purge => "purge",
+            Outcome::Forwarded { .. } => "forwarded",
+            Outcome::UpstreamError { .. } => "upstream_error",
+        }
+    }
+}
+
+/// A request on its way through the proxy, observed once it's answered.
+pub struct Pending {
+    id: u64,
+    started: Instant,
+    time: DateTime<Utc>,
+    method: String,
+    version: Version,
+    path: String,
+    query: String,
+    remote: Option<SocketAddr>,
+    /// The request headers, if the journal or the access log needs them.
+    headers: Option<HeaderMap>,
+    /// The length of the request body as received.
+    body_len: usize,
+    /// The request body for the journal, as forwarded once the request body
+    /// rules ran.
+    body: Bytes,
+}
+
+impl Pending {
+    /// Replaces the body the journal keeps with the one sent upstream.
+    pub fn forwarded_body(&mut self, body: &Bytes) {
+        self.body = body.clone();
+    }
+}
+
+/// Sees every exchange once it's answered, and passes it on to the metrics,
+/// the exchange log, the access log and the request journal.
+#[derive(Debug)]
+pub struct Observer {
+    next_id: AtomicU64,
+    journal: Arc<Journal>,
+    access_log: Option<AccessLog>,
+}
+
+impl Observer {
+    pub fn new(journal: Arc<Journal>, access_log: Option<AccessLog>) -> Observer {
+        Observer {
+            next_id: AtomicU64::new(0),
+            journal,
+            access_log,
+        }
+    }
+
+    /// Notes an incoming request.
+    #[allow(clippy::too_many_arguments)]
+    pub fn start(
+        &self,
+        remote: Option<SocketAddr>,
+        method: &Method,
+        version: Version,
+        path: &str,
+        query: &str,
+        headers: &HeaderMap,
+        body: &Bytes,
+    ) -> Pending {
+        let keep_headers = self.journal.is_enabled() || self.access_log.is_some();
+        Pending {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            started: Instant::now(),
+            time: Utc::now(),
+            method: method.to_string(),
+            version,
+            path: path.to_string(),
+            query: query.to_string(),
+            remote,
+            headers: keep_headers.then(|| headers.clone()),
+            body_len: body.len(),
+            body: body.clone(),
+        }
+    }
+
+    /// Passes the response a request got on, and hands it back.
+    pub fn finish(
+        &self,
+        pending: Pending,
+        outcome: Outcome,
+        response: Response<Bytes>,
+    ) -> Response<Bytes> {
+        let duration = pending.started.elapsed();
+        let route = match outcome {
+            Outcome::Mock { ref mock_path, .. } => mock_path.clone(),
+            _ => metrics::route_of(&pending.path),
+        };
+        metrics::METRICS.observe_request(
+            &pending.method,
+            &route,
+            response.status().as_u16(),
+            outcome.kind(),
+            pending.body_len,
+            response.body().len(),
+        );
+        if log_enabled!(target: logging::EXCHANGE, Level::Info) {
+            info!(
+                target: logging::EXCHANGE,
+                "{}",
+                exchange_line(&pending, duration, &outcome, &response)
+            );
+        }
+        if let (Some(access_log), Some(request_headers)) = (&self.access_log, &pending.headers) {
+            access_log.write(&access_log::Exchange {
+                remote: pending.remote,
+                time: pending.time,
+                method: &pending.method,
+                version: pending.version,
+                path: &pending.path,
+                query: &pending.query,
+                request_headers,
+                status: response.status().as_u16(),
+                response_headers: response.headers(),
+                response_len: response.body().len(),
+                duration,
+            });
+        }
+
+        if self.journal.is_enabled() {
+            self.journal.record(Entry {
+                id: pending.id,
+                time: pending.time,
+                duration_ms: duration.as_secs_f64() * 1000.0,
+                request_headers: pending
+                    .headers
+                    .as_ref()
+                    .map(journal::header_map)
+                    .unwrap_or_default(),
+                request_body: journal::body_text(&pending.body),
+                method: pending.method,
+                path: pending.path,
+                query: pending.query,
+                status: response.status().as_u16(),
+                response_headers: journal::header_map(response.headers()),
+                response_body: journal::body_text(response.body()),
+                outcome,
+            });
+        }
+        response
+    }
+}
+
+/// The exchange log line: one JSON object summing up a request and its response.
+fn exchange_line(
+    pending: &Pending,
+    duration: Duration,
+    outcome: &Outcome,
+    response: &Response<Bytes>,
+) -> serde_json::Value {
+    let url = if pending.query.is_empty() {
+        pending.path.clone()
+    } else {
+        format!("{}?{}", pending.path, pending.query)
+    };
+    let mut line = json!({
+        "timestamp": pending.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "request_id": pending.id,
+        "method": pending.method,
+        "url": url,
+        "status": response.status().as_u16(),
+        "duration_ms": duration.as_secs_f64() * 1000.0,
+        "request_bytes": pending.body_len,
+        "response_bytes": response.body().len(),
+        "outcome": outcome.kind(),
+    });
+    match outcome {
+        Outcome::Mock { mock_id, mock_path } => {
+            line["mock"] = json!({ "id": mock_id, "path": mock_path });
+        }
+        Outcome::Forwarded {
+            url,
+            cache,
+            upstream_ms,
+        } => {
+            line["upstream"] = json!(url);
+            if let Some(upstream_ms) = upstream_ms {
+                line["upstream_ms"] = json!(upstream_ms);
+            }
+            if let Some(cache) = cache {
+                line["cache"] = json!(cache);
+            }
+        }
+        Outcome::UpstreamError { url } => line["upstream"] = json!(url),
+        Outcome::Static | Outcome::Preflight | Outcome::Purge => {}
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journals_the_forwarded_request_body() {
+        let journal = Arc::new(Journal::new(10));
+        let observer = Observer::new(journal.clone(), None);
+        let mut pending = observer.start(
+            None,
+            &Method::POST,
+            Version::HTTP_11,
+            "/v1/users",
+            "",
+            &HeaderMap::new(),
+            &Bytes::from_static(b"tenant=prod"),
+        );
+        pending.forwarded_body(&Bytes::from_static(b"tenant=test"));
+        let outcome = Outcome::Forwarded {
+            url: "http://upstream/v1/users".to_string(),
+            cache: None,
+            upstream_ms: None,
+        };
+        observer.finish(pending, outcome, Response::new(Bytes::new()));
+        let entries = journal.query(&journal::Query::default());
+        assert_eq!(entries[0].request_body, "tenant=test");
+    }
+}
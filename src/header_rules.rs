@@ -0,0 +1,310 @@
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use warp::http::header::HeaderName;
+use warp::http::{HeaderMap, HeaderValue, Method};
+
+/// A header rewrite rule (loaded from the mock config file).
+/// Rules under `[[request_headers]]` apply to requests going upstream, rules
+/// under `[[response_headers]]` to responses going back to the client.
+/// For example, from TOML:
+///
+/// [[request_headers]]
+/// action = "set"
+/// name = "Authorization"
+/// value = "Bearer local-dev-token"
+///
+/// [[response_headers]]
+/// action = "remove"
+/// name = "Strict-Transport-Security"
+///
+/// [[response_headers]]
+/// path = "/v1/*"
+/// action = "replace"
+/// name = "Location"
+/// pattern = "^https://api\\.example\\.com"
+/// replacement = "http://localhost:6969"
+#[derive(Debug, Deserialize, Clone)]
+pub struct HeaderRule {
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(flatten)]
+    pub action: HeaderAction,
+}
+
+/// What a header rule does to the matching header. Names and values are
+/// checked while the config file is parsed, so bad rules are reported at
+/// startup.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum HeaderAction {
+    /// Replaces every value of the header with `value`.
+    Set {
+        #[serde(deserialize_with = "deserialize_name")]
+        name: HeaderName,
+        #[serde(deserialize_with = "deserialize_value")]
+        value: HeaderValue,
+    },
+    /// Adds `value` next to any existing values of the header.
+    Append {
+        #[serde(deserialize_with = "deserialize_name")]
+        name: HeaderName,
+        #[serde(deserialize_with = "deserialize_value")]
+        value: HeaderValue,
+    },
+    /// Drops the header.
+    Remove {
+        #[serde(deserialize_with = "deserialize_name")]
+        name: HeaderName,
+    },
+    /// Moves the values of the header to the header `to`.
+    Rename {
+        #[serde(deserialize_with = "deserialize_name")]
+        name: HeaderName,
+        #[serde(deserialize_with = "deserialize_name")]
+        to: HeaderName,
+    },
+    /// Runs a regex replacement over every value of the header.
+    Replace {
+        #[serde(deserialize_with = "deserialize_name")]
+        name: HeaderName,
+        #[serde(deserialize_with = "deserialize_regex")]
+        pattern: Regex,
+        replacement: String,
+    },
+}
+
+impl HeaderRule {
+    fn applies_to(&self, method: &Method, path: &str) -> bool {
+        self.method
+            .as_ref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(method.as_str()))
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|p| crate::path_matches(p, path))
+    }
+}
+
+/// Applies every rule scoped to the method and path, in order.
+pub fn apply(rules: &[HeaderRule], method: &Method, path: &str, headers: &mut HeaderMap) {
+    for rule in rules.iter().filter(|r| r.applies_to(method, path)) {
+        apply_action(&rule.action, headers);
+    }
+}
+
+fn apply_action(action: &HeaderAction, headers: &mut HeaderMap) {
+    match action {
+        HeaderAction::Set { name, value } => {
+            headers.insert(name.clone(), value.clone());
+        }
+        HeaderAction::Append { name, value } => {
+            headers.append(name.clone(), value.clone());
+        }
+        HeaderAction::Remove { name } => {
+            headers.remove(name);
+        }
+        HeaderAction::Rename { name, to } => {
+            let values: Vec<HeaderValue> = headers.get_all(name).iter().cloned().collect();
+            headers.remove(name);
+            for value in values {
+                headers.append(to.clone(), value);
+            }
+        }
+        HeaderAction::Replace {
+            name,
+            pattern,
+            replacement,
+        } => {
+            // A value that can't be rewritten is kept as it was.
+            let values: Vec<HeaderValue> = headers
+                .get_all(name)
+                .iter()
+                .map(|value| {
+                    let Ok(text) = value.to_str() else {
+                        warn!("Keeping non-UTF-8 value of header {}", name);
+                        return value.clone();
+                    };
+                    let replaced = pattern.replace_all(text, replacement.as_str());
+                    HeaderValue::from_str(&replaced).unwrap_or_else(|e| {
+                        warn!(
+                            "Keeping header {} unchanged: invalid header value {:?}: {}",
+                            name, replaced, e
+                        );
+                        value.clone()
+                    })
+                })
+                .collect();
+            if values.is_empty() {
+                return;
+            }
+            headers.remove(name);
+            for value in values {
+                headers.append(name.clone(), value);
+            }
+        }
+    }
+}
+
+fn deserialize_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HeaderName, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|e| serde::de::Error::custom(format!("invalid header name {:?}: {}", name, e)))
+}
+
+fn deserialize_value<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HeaderValue, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    HeaderValue::from_str(&value)
+        .map_err(|e| serde::de::Error::custom(format!("invalid header value {:?}: {}", value, e)))
+}
+
+/// Compiles regex patterns while the config file is parsed, so bad patterns
+/// are reported at startup.
+pub fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::headers;
+
+    fn rules(toml: &str) -> Vec<HeaderRule> {
+        #[derive(Deserialize)]
+        struct File {
+            rules: Vec<HeaderRule>,
+        }
+        toml::from_str::<File>(toml).unwrap().rules
+    }
+
+    fn values(headers: &HeaderMap, name: &str) -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+            .collect()
+    }
+
+    fn run(toml: &str, headers: &mut HeaderMap) {
+        apply(&rules(toml), &Method::GET, "/v1/users", headers);
+    }
+
+    #[test]
+    fn sets_appends_and_removes() {
+        let mut h = headers(&[("accept", "text/html"), ("x-debug", "1"), ("x-debug", "2")]);
+        run(
+            r#"
+            [[rules]]
+            action = "set"
+            name = "Accept"
+            value = "application/json"
+
+            [[rules]]
+            action = "append"
+            name = "X-Trace"
+            value = "a"
+
+            [[rules]]
+            action = "append"
+            name = "x-trace"
+            value = "b"
+
+            [[rules]]
+            action = "remove"
+            name = "X-DEBUG"
+            "#,
+            &mut h,
+        );
+        assert_eq!(values(&h, "accept"), ["application/json"]);
+        assert_eq!(values(&h, "x-trace"), ["a", "b"]);
+        assert!(!h.contains_key("x-debug"));
+    }
+
+    #[test]
+    fn renames_every_value() {
+        let mut h = headers(&[("x-old", "1"), ("x-old", "2")]);
+        run(
+            r#"
+            [[rules]]
+            action = "rename"
+            name = "X-Old"
+            to = "X-New"
+            "#,
+            &mut h,
+        );
+        assert!(!h.contains_key("x-old"));
+        assert_eq!(values(&h, "x-new"), ["1", "2"]);
+    }
+
+    #[test]
+    fn replaces_with_a_regex() {
+        let mut h = headers(&[("location", "https://api.example.com/v1/users/7")]);
+        run(
+            r#"
+            [[rules]]
+            action = "replace"
+            name = "LOCATION"
+            pattern = "^https://api\\.example\\.com"
+            replacement = "http://localhost:6969"
+            "#,
+            &mut h,
+        );
+        assert_eq!(values(&h, "location"), ["http://localhost:6969/v1/users/7"]);
+    }
+
+    #[test]
+    fn keeps_values_that_cannot_be_replaced() {
+        let mut h = headers(&[("x-token", "abc")]);
+        h.append("x-token", HeaderValue::from_bytes(b"caf\xe9").unwrap());
+        run(
+            r#"
+            [[rules]]
+            action = "replace"
+            name = "X-Token"
+            pattern = "b"
+            replacement = "\n"
+            "#,
+            &mut h,
+        );
+        assert_eq!(values(&h, "x-token"), ["abc", "caf\u{fffd}"]);
+    }
+
+    #[test]
+    fn scopes_rules_to_method_and_path() {
+        let rules = rules(
+            r#"
+            [[rules]]
+            method = "POST"
+            action = "remove"
+            name = "X-A"
+
+            [[rules]]
+            path = "/v2/*"
+            action = "remove"
+            name = "X-A"
+            "#,
+        );
+        let mut h = headers(&[("x-a", "1")]);
+        apply(&rules, &Method::GET, "/v1/users", &mut h);
+        assert!(h.contains_key("x-a"));
+        apply(&rules, &Method::GET, "/v2/users", &mut h);
+        assert!(!h.contains_key("x-a"));
+    }
+
+    #[test]
+    fn rejects_bad_names_and_values_when_parsed() {
+        #[derive(Debug, Deserialize)]
+        struct File {
+            #[allow(dead_code)]
+            rules: Vec<HeaderRule>,
+        }
+        let parse = |toml: &str| toml::from_str::<File>(toml).unwrap_err().to_string();
+        let err = parse("[[rules]]\naction = \"remove\"\nname = \"Bad Name\"\n");
+        assert!(err.contains("invalid header name \"Bad Name\""), "{}", err);
+        let err = parse("[[rules]]\naction = \"set\"\nname = \"X-A\"\nvalue = \"a\\nb\"\n");
+        assert!(err.contains("invalid header value"), "{}", err);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::exchange::Outcome;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use warp::http::HeaderMap;
+
+/// Bodies longer than this are cut short in the journal.
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// One request and the response it got.
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    pub id: u64,
+    pub time: DateTime<Utc>,
+    pub duration_ms: f64,
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub request_headers: BTreeMap<String, String>,
+    pub request_body: String,
+    pub status: u16,
+    pub response_headers: BTreeMap<String, String>,
+    pub response_body: String,
+    pub outcome: Outcome,
+}
+
+/// Filters for `GET /journal`.
+#[derive(Debug, Deserialize, Default)]
+pub struct Query {
+    pub method: Option<String>,
+    /// A path pattern, where `*` matches anything.
+    pub path: Option<String>,
+    pub status: Option<u16>,
+    /// `mock`, `static`, `preflight`, `purge`, `forwarded` or `upstream_error`.
+    pub outcome: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only the most recent entries matching the other filters.
+    pub limit: Option<usize>,
+}
+
+/// An in-memory ring buffer of the most recent exchanges (`--journal-size`),
+/// filled by the exchange observer.
+#[derive(Debug)]
+pub struct Journal {
+    capacity: usize,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl Journal {
+    /// A journal keeping `capacity` entries; 0 turns it off.
+    pub fn new(capacity: usize) -> Journal {
+        Journal {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Adds an entry, dropping the oldest one if the journal is full.
+    pub fn record(&self, entry: Entry) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The entries matching every filter of the query, oldest first.
+    pub fn query(&self, query: &Query) -> Vec<Entry> {
+        let entries = self.entries.lock().unwrap();
+        let mut matching: Vec<Entry> = entries
+            .iter()
+            .filter(|e| {
+                query
+                    .method
+                    .as_ref()
+                    .is_none_or(|m| m.eq_ignore_ascii_case(&e.method))
+                    && query
+                        .path
+                        .as_ref()
+                        .is_none_or(|p| crate::path_matches(p, &e.path))
+                    && query.status.is_none_or(|s| s == e.status)
+                    && query
+                        .outcome
+                        .as_ref()
+                        .is_none_or(|o| *o == e.outcome.kind())
+                    && query.since.is_none_or(|since| e.time >= since)
+                    && query.until.is_none_or(|until| e.time <= until)
+            })
+            .cloned()
+            .collect();
+        if let Some(limit) = query.limit {
+            let skip = matching.len().saturating_sub(limit);
+            matching.drain(..skip);
+        }
+        matching
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+pub fn header_map(headers: &HeaderMap) -> BTreeMap<String, String> {
+    let mut map = BTreeMap::new();
+    for (name, value) in headers {
+        let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+        map.entry(name.to_string())
+            .and_modify(|v: &mut String| {
+                v.push_str(", ");
+                v.push_str(&value);
+            })
+            .or_insert(value);
+    }
+    map
+}
+
+pub fn body_text(body: &[u8]) -> String {
+    if body.len() > MAX_BODY_LEN {
+        format!(
+            "{}... ({} bytes)",
+            String::from_utf8_lossy(&body[..MAX_BODY_LEN]),
+            body.len()
+        )
+    } else {
+        String::from_utf8_lossy(body).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry(id: u64, method: &str, path: &str, status: u16, outcome: Outcome) -> Entry {
+        Entry {
+            id,
+            time: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, id as u32).unwrap(),
+            duration_ms: 1.0,
+            method: method.to_string(),
+            path: path.to_string(),
+            query: String::new(),
+            request_headers: BTreeMap::new(),
+            request_body: String::new(),
+            status,
+            response_headers: BTreeMap::new(),
+            response_body: String::new(),
+            outcome,
+        }
+    }
+
+    fn journal() -> Journal {
+        let journal = Journal::new(10);
+        let forwarded = || Outcome::Forwarded {
+            url: "http://upstream".to_string(),
+            cache: None,
+            upstream_ms: Some(1.0),
+        };
+        let mock = Outcome::Mock {
+            mock_id: 1,
+            mock_path: "/v1/users/{id}".to_string(),
+        };
+        journal.record(entry(1, "GET", "/v1/users/1", 200, mock));
+        journal.record(entry(2, "POST", "/v1/users", 201, forwarded()));
+        journal.record(entry(3, "GET", "/v1/orders", 404, forwarded()));
+        journal.record(entry(4, "GET", "/health", 200, Outcome::Static));
+        journal
+    }
+
+    fn ids(journal: &Journal, query: Query) -> Vec<u64> {
+        journal.query(&query).iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn filters_entries() {
+        let journal = journal();
+        assert_eq!(ids(&journal, Query::default()), [1, 2, 3, 4]);
+        let query = |method: Option<&str>, path: Option<&str>| Query {
+            method: method.map(str::to_string),
+            path: path.map(str::to_string),
+            ..Query::default()
+        };
+        assert_eq!(ids(&journal, query(Some("get"), None)), [1, 3, 4]);
+        assert_eq!(ids(&journal, query(None, Some("/v1/*"))), [1, 2, 3]);
+        assert_eq!(ids(&journal, query(Some("GET"), Some("/v1/*"))), [1, 3]);
+        let status = Query {
+            status: Some(200),
+            ..Query::default()
+        };
+        assert_eq!(ids(&journal, status), [1, 4]);
+        let outcome = |kind: &str| Query {
+            outcome: Some(kind.to_string()),
+            ..Query::default()
+        };
+        assert_eq!(ids(&journal, outcome("forwarded")), [2, 3]);
+        assert_eq!(ids(&journal, outcome("mock")), [1]);
+        assert_eq!(ids(&journal, outcome("purge")), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn filters_by_time_and_keeps_the_latest() {
+        let journal = journal();
+        let at = |second| Some(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, second).unwrap());
+        let window = Query {
+            since: at(2),
+            until: at(3),
+            ..Query::default()
+        };
+        assert_eq!(ids(&journal, window), [2, 3]);
+        let limit = Query {
+            method: Some("GET".to_string()),
+            limit: Some(2),
+            ..Query::default()
+        };
+        assert_eq!(ids(&journal, limit), [3, 4]);
+    }
+
+    #[test]
+    fn drops_the_oldest_entries_when_full() {
+        let journal = Journal::new(2);
+        for id in 1..=3 {
+            journal.record(entry(id, "GET", "/", 200, Outcome::Static));
+        }
+        assert_eq!(ids(&journal, Query::default()), [2, 3]);
+        journal.clear();
+        assert!(ids(&journal, Query::default()).is_empty());
+
+        let off = Journal::new(0);
+        off.record(entry(1, "GET", "/", 200, Outcome::Static));
+        assert!(!off.is_enabled());
+        assert!(ids(&off, Query::default()).is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+use chrono::{SecondsFormat, Utc};
+use clap::ArgEnum;
+use std::io::Write;
+
+/// Log target of the per-request narration (headers, bodies, mock matching).
+pub const REQUEST: &str = "proxxyy::request";
+
+/// Log target of the one-line summary of each exchange, written as JSON.
+pub const EXCHANGE: &str = "proxxyy::exchange";
+
+/// How log lines are written (`--log-format`).
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Colored, human-oriented lines.
+    Text,
+    /// One JSON object per line, and one per exchange.
+    Json,
+}
+
+impl LogFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+/// Sets up logging. `RUST_LOG` filters what's logged, on top of the defaults:
+/// everything at info level, except that text logs leave out the exchange
+/// summaries and JSON logs leave out the per-request narration.
+pub fn init(format: LogFormat) {
+    let defaults = match format {
+        LogFormat::Text => format!("info,{}=off", EXCHANGE),
+        LogFormat::Json => format!("info,{}=off", REQUEST),
+    };
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(&defaults);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            // Exchange summaries are JSON objects already.
+            if record.target() == EXCHANGE {
+                return writeln!(buf, "{}", record.args());
+            }
+            let line = serde_json::json!({
+                "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": strip_ansi(&record.args().to_string()),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+/// Removes the color codes `colored` puts into messages.
+fn strip_ansi(message: &str) -> String {
+    let mut plain = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // Skip the escape sequence up to its final letter.
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_color_codes() {
+        assert_eq!(strip_ansi("GET /v1/users 200"), "GET /v1/users 200");
+        assert_eq!(
+            strip_ansi("\u{1b}[1;32mGET\u{1b}[0m /v1/users \u{1b}[33m404\u{1b}[0m"),
+            "GET /v1/users 404"
+        );
+        assert_eq!(
+            strip_ansi("caf\u{e9} \u{1b}[2m\u{2192}\u{1b}[0m"),
+            "caf\u{e9} \u{2192}"
+        );
+        // An unterminated sequence swallows the rest of the message.
+        assert_eq!(strip_ansi("done \u{1b}[1;3"), "done ");
+    }
+}
@@ -1,40 +1,147 @@
+mod access_log;
+mod admin;
+mod body_rules;
+mod cache;
+mod config_file;
+mod content_type;
+mod cors;
+mod coverage;
+mod exchange;
+mod header_rules;
+mod journal;
+mod logging;
+mod metrics;
+mod mock;
+mod mock_config;
+mod mock_dir;
+mod near_miss;
+mod shadow;
+mod shutdown;
+mod split;
+mod startup;
+mod static_files;
+#[cfg(test)]
+mod test_support;
+mod validate;
+
+use access_log::AccessLog;
+use admin::Admin;
+use body_rules::BodyRule;
 use bytes::Bytes;
-use chrono;
-use clap::Parser;
+use cache::{Lookup, ResponseCache};
+use clap::{Parser, Subcommand};
 use colored::Colorize;
+use cors::Cors;
+use exchange::{Observer, Outcome};
+use header_rules::HeaderRule;
+use journal::Journal;
 use log::{error, info, warn};
+use logging::LogFormat;
+use mock::{MockSource, MockStore};
 use reqwest::Client;
-use serde::Deserialize;
-use serde_json;
-use std::collections::{BTreeMap, HashMap};
+use shadow::Shadow;
+use shutdown::{InFlight, InFlightGuard};
+use split::SplitRule;
+use startup::{Category, Problems};
+use static_files::StaticDir;
+use std::collections::BTreeMap;
 use std::convert::Infallible;
 use std::fs;
-use std::net::SocketAddr;
-use std::path::Path;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use url::Url;
+use warp::http::Version;
+use warp::hyper;
+use warp::hyper::server::conn::{AddrIncoming, AddrStream};
+use warp::hyper::service::{make_service_fn, service_fn, Service};
 use warp::Filter;
 
 /// Main configuration for the proxy, including optional mock config file.
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
 struct Config {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// (Optional) Path to a proxy config file holding any of these options,
+    /// plus mocks.
+    ///
+    /// Defaults to `proxxyy.toml` in the working directory, if there is one.
+    /// `${VAR}` references in the file are replaced by environment variables,
+    /// and command-line flags win over the file.
+    #[clap(long = "config")]
+    config: Option<String>,
+
+    /// (Optional) Named profile from the config file to run with.
+    ///
+    /// A profile's options are layered over the top-level ones in the file,
+    /// and over the profile it `inherits` from.
+    #[clap(long = "profile")]
+    profile: Option<String>,
+
+    /// (Optional) Start even if the configuration has problems.
+    ///
+    /// By default the proxy checks its options, mock files and directories at
+    /// startup, reports every problem, and exits with code 2 (options), 3 (mock
+    /// files) or 4 (files and directories). With this flag, the problems are
+    /// logged as warnings and the proxy starts anyway.
+    #[clap(long = "lenient", overrides_with = "no_lenient")]
+    lenient: bool,
+
+    /// (Optional) Turn off `--lenient`, e.g. when the config file turns it on.
+    #[clap(long = "no-lenient", overrides_with = "lenient")]
+    no_lenient: bool,
+
+    /// (Optional) URL on which to serve the admin API, e.g. http://localhost:6970.
+    ///
+    /// The admin API lists, adds, updates and deletes mocks, switches the
+    /// target URL, toggles header/body logging and starts or stops recording,
+    /// all without a restart.
+    #[clap(long = "admin-url")]
+    admin_url: Option<String>,
+
+    /// (Optional) How log lines are written: `text` or `json`.
+    ///
+    /// `json` writes one JSON object per line, plus one per exchange with its
+    /// timing, sizes, mock and upstream, instead of the per-request narration.
+    /// Set `RUST_LOG` to choose what's logged. Command line only, since
+    /// logging starts before the config file is read.
+    #[clap(long = "log-format", arg_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// (Optional) How many recent requests the journal keeps for the admin API.
+    ///
+    /// Each entry holds the request, the response, timing and whether a mock,
+    /// a static file or the target answered. 0 turns the journal off.
+    /// Defaults to 500.
+    #[clap(long = "journal-size")]
+    journal_size: Option<usize>,
+
     /// The target URL to which requests will be proxied.
     #[clap(long = "target-url", short = 't')]
-    target_url: String,
+    target_url: Option<String>,
 
     /// The API URL on which the proxy server will run.
     #[clap(long = "api-url", short = 'u')]
-    api_url: String,
+    api_url: Option<String>,
 
     /// (Optional) Flag to add CORS headers to responses.
     ///
-    /// When enabled, the proxy will add headers such as
-    /// Content-Type, Access-Control-Allow-Origin,
-    /// Access-Control-Allow-Methods, and
-    /// Access-Control-Allow-Headers.
-    #[clap(long = "add-cors-headers", short = 'c')]
+    /// When enabled, the proxy answers CORS preflights itself and allows any
+    /// origin on routes not covered by a `[[cors]]` rule in the mock config.
+    #[clap(
+        long = "add-cors-headers",
+        short = 'c',
+        overrides_with = "no_add_cors_headers"
+    )]
     add_cors_headers: bool,
 
+    /// (Optional) Turn off `--add-cors-headers`, e.g. when the config file turns it on.
+    #[clap(long = "no-add-cors-headers", overrides_with = "add_cors_headers")]
+    no_add_cors_headers: bool,
+
     /// (Optional) Extra headers to add to responses.
     ///
     /// Format: "Header-Name: value". Can be repeated. For example:
@@ -42,11 +149,73 @@ struct Config {
     #[clap(long = "extra-header", short = 'e')]
     extra_headers: Vec<String>,
 
-    /// (Optional) Path to a TOML file describing mock endpoints.
+    /// (Optional) Path to a TOML, YAML or JSON file describing mock endpoints.
     ///
     /// If provided, the proxy will check for a matching mock before forwarding.
+    /// Can be repeated; mocks from later files win over earlier ones.
     #[clap(long = "mock-config", short = 'm')]
-    mock_config: Option<String>,
+    mock_config: Vec<String>,
+
+    /// (Optional) Directory tree whose files become mocks.
+    ///
+    /// For example, `mocks/GET/v1/users/__id__.json` answers `GET /v1/users/{id}`
+    /// and `mocks/v1/users.POST.201.json` answers `POST /v1/users` with a 201.
+    /// The directory is watched, so added or edited files are picked up live.
+    #[clap(long = "mock-dir")]
+    mock_dir: Option<String>,
+
+    /// (Optional) Serve a local directory under a URL prefix.
+    ///
+    /// Format: "PREFIX=DIR". Can be repeated. For example:
+    /// --static-dir='/assets=./dist'
+    /// Files are served with content types, Range support and ETags; requests
+    /// for missing files fall through to the target.
+    #[clap(long = "static-dir")]
+    static_dirs: Vec<String>,
+
+    /// (Optional) Where to write a mock coverage report when the proxy stops.
+    ///
+    /// On Ctrl-C or SIGTERM, the hit count of every mock and the requests no
+    /// mock answered are written to this path as `.txt` and `.json` files.
+    /// For example: --coverage-report=target/mock-coverage
+    #[clap(long = "coverage-report")]
+    coverage_report: Option<String>,
+
+    /// (Optional) How long, in seconds, to wait for in-flight requests on shutdown.
+    ///
+    /// On Ctrl-C or SIGTERM the proxy stops accepting connections and lets the
+    /// requests it's answering finish, for at most this long. A second Ctrl-C
+    /// stops it right away.
+    /// Defaults to 10 seconds.
+    #[clap(long = "shutdown-timeout")]
+    shutdown_timeout: Option<u64>,
+
+    /// (Optional) File to which an access log line is appended for each request.
+    ///
+    /// Separate from the console logs, in the format of --access-log-format.
+    #[clap(long = "access-log")]
+    access_log: Option<String>,
+
+    /// (Optional) Format of the access log lines.
+    ///
+    /// `common` or `combined` for Apache's Common/Combined Log Format, or a
+    /// format string with Apache directives such as `%h %t "%r" %>s %b %D`.
+    /// Defaults to `combined`.
+    #[clap(long = "access-log-format")]
+    access_log_format: Option<String>,
+
+    /// (Optional) Size at which the access log is rotated, e.g. `10MB` or `512K`.
+    #[clap(long = "access-log-max-size")]
+    access_log_max_size: Option<String>,
+
+    /// (Optional) Rotate the access log every `hourly` or `daily`.
+    #[clap(long = "access-log-rotate")]
+    access_log_rotate: Option<String>,
+
+    /// (Optional) How many rotated access logs to keep, as `FILE.1` (newest) to `FILE.N`.
+    /// Defaults to 5.
+    #[clap(long = "access-log-keep")]
+    access_log_keep: Option<usize>,
 
     /// (Optional) Directory to save incoming requests as JSON files.
     ///
@@ -58,63 +227,193 @@ struct Config {
     /// (Optional) Show request headers in logs.
     ///
     /// When enabled, request headers will be logged. By default, headers are hidden.
-    #[clap(long = "show-headers", short = 'h')]
+    #[clap(long = "show-headers", short = 'h', overrides_with = "no_show_headers")]
     show_headers: bool,
 
+    /// (Optional) Turn off `--show-headers`, e.g. when the config file turns it on.
+    #[clap(long = "no-show-headers", overrides_with = "show_headers")]
+    no_show_headers: bool,
+
     /// (Optional) Show request bodies in logs.
     ///
     /// When enabled, request bodies will be logged. By default, bodies are hidden.
-    #[clap(long = "show-body", short = 'b')]
+    #[clap(long = "show-body", short = 'b', overrides_with = "no_show_body")]
     show_body: bool,
+
+    /// (Optional) Turn off `--show-body`, e.g. when the config file turns it on.
+    #[clap(long = "no-show-body", overrides_with = "show_body")]
+    no_show_body: bool,
+
+    /// (Optional) Cache upstream responses to GET and HEAD requests.
+    ///
+    /// Cached responses are served without contacting the target until they
+    /// expire. Expired responses carrying an ETag or Last-Modified header are
+    /// revalidated with a conditional request. Send `PURGE <path>` to the proxy
+    /// to drop entries (the path may contain `*` wildcards).
+    #[clap(long = "cache", overrides_with = "no_cache")]
+    cache: bool,
+
+    /// (Optional) Turn off `--cache`, e.g. when the config file turns it on.
+    #[clap(long = "no-cache", overrides_with = "cache")]
+    no_cache: bool,
+
+    /// (Optional) Default time-to-live, in seconds, for cached responses.
+    ///
+    /// Used when neither a `[[cache]]` rule nor the upstream's Cache-Control
+    /// max-age says otherwise.
+    /// Defaults to 60 seconds.
+    #[clap(long = "cache-ttl")]
+    cache_ttl: Option<u64>,
+
+    /// (Optional) Directory in which cached responses are persisted.
+    ///
+    /// If provided, the cache survives restarts of the proxy.
+    #[clap(long = "cache-dir")]
+    cache_dir: Option<String>,
+
+    /// (Optional) Request header that becomes part of the cache key.
+    ///
+    /// Can be repeated. For example: --cache-key-header=Authorization
+    #[clap(long = "cache-key-header")]
+    cache_key_headers: Vec<String>,
+
+    /// (Optional) Shadow URL to which forwarded requests are mirrored.
+    ///
+    /// Every request forwarded to the target is also sent, in the background,
+    /// to this URL. Clients only ever get the target's response; differences
+    /// between both responses are logged.
+    #[clap(long = "shadow-url")]
+    shadow_url: Option<String>,
+
+    /// (Optional) Response header compared between target and shadow.
+    ///
+    /// Can be repeated. For example: --shadow-compare-header=Content-Type
+    #[clap(long = "shadow-compare-header")]
+    shadow_compare_headers: Vec<String>,
+
+    /// (Optional) JSON pointer ignored when comparing shadow response bodies.
+    ///
+    /// Can be repeated, and `*` matches any key or array index. For example:
+    /// --shadow-ignore-path='/meta/timestamp' --shadow-ignore-path='/items/*/id'
+    #[clap(long = "shadow-ignore-path")]
+    shadow_ignore_paths: Vec<String>,
+
+    /// (Optional) File to which shadow differences are appended as JSON lines.
+    #[clap(long = "shadow-diff-file")]
+    shadow_diff_file: Option<String>,
+}
+
+/// Subcommands run instead of the proxy.
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Work with mock config files.
+    Mocks {
+        #[clap(subcommand)]
+        action: MocksCommand,
+    },
 }
 
-/// A single mock rule (loaded from the config file).
-/// For example, from TOML:
-///
-/// [[mocks]]
-/// method = "GET"
-/// path = "/v1/mock"
-/// status = 200
-/// body = "Mocked body."
-///
-/// [mocks.headers]
-/// X-My-Header = "123"
-#[derive(Debug, Deserialize, Clone)]
-struct Mock {
-    method: String,
-    path: String,
-    #[serde(default = "default_status")]
-    status: u16,
-    #[serde(default)]
-    body: String,
-    #[serde(default)]
-    headers: HashMap<String, String>,
+#[derive(Subcommand, Debug, Clone)]
+enum MocksCommand {
+    /// Check mock config files (and their includes) for problems.
+    ///
+    /// Reports parse errors, duplicate and unreachable mocks, missing body
+    /// files, invalid status codes and invalid headers, with line numbers.
+    /// Exits with status 1 if anything is wrong.
+    Validate {
+        /// Mock config files to check.
+        #[clap(required = true)]
+        files: Vec<String>,
+    },
+}
+
+/// Header and body rewrite rules for both directions of the proxied traffic.
+#[derive(Debug, Default)]
+struct RewriteRules {
+    request_headers: Vec<HeaderRule>,
+    response_headers: Vec<HeaderRule>,
+    request_body: Vec<BodyRule>,
+    response_body: Vec<BodyRule>,
+}
+
+/// A filter to pass a snapshot of the (runtime-adjustable) configuration to each request.
+fn with_config(
+    config: Arc<RwLock<Config>>,
+) -> impl Filter<Extract = (Config,), Error = Infallible> + Clone {
+    warp::any().map(move || config.read().unwrap().clone())
+}
+
+/// Opens the `--access-log` file, noting any problem with its options.
+fn open_access_log(file: &str, config: &Config, problems: &mut Problems) -> Option<AccessLog> {
+    let format =
+        access_log::parse_format(config.access_log_format.as_deref().unwrap_or("combined"));
+    let max_size = config
+        .access_log_max_size
+        .as_deref()
+        .map(access_log::parse_size)
+        .transpose();
+    let period = config
+        .access_log_rotate
+        .as_deref()
+        .map(access_log::parse_period)
+        .transpose();
+    let keep = access_log::check_keep(config.access_log_keep.unwrap_or(5));
+    let (format, max_size, period, keep) = match (format, max_size, period, keep) {
+        (Ok(format), Ok(max_size), Ok(period), Ok(keep)) => (format, max_size, period, keep),
+        (format, max_size, period, keep) => {
+            for err in [format.err(), max_size.err(), period.err(), keep.err()]
+                .into_iter()
+                .flatten()
+            {
+                problems.push(Category::Options, err);
+            }
+            return None;
+        }
+    };
+    let rotation = access_log::Rotation {
+        max_size,
+        period,
+        keep,
+    };
+    match AccessLog::open(file, format, rotation) {
+        Ok(access_log) => Some(access_log),
+        Err(e) => {
+            problems.push(
+                Category::Filesystem,
+                format!("Cannot write to access log {}: {}", file, e),
+            );
+            None
+        }
+    }
 }
 
-fn default_status() -> u16 {
-    200
+/// A filter to pass the client's address and the request's HTTP version,
+/// which the server puts into the request extensions.
+fn with_connection(
+) -> impl Filter<Extract = ((Option<SocketAddr>, Version),), Error = Infallible> + Clone {
+    warp::ext::optional::<SocketAddr>()
+        .and(warp::ext::optional::<Version>())
+        .map(|remote, version: Option<Version>| (remote, version.unwrap_or_default()))
 }
 
-/// The top-level structure of the TOML file:
-/// e.g.
-/// [[mocks]]
-/// method = "GET"
-/// ...
-#[derive(Debug, Deserialize, Clone)]
-struct MockFile {
-    #[serde(default)]
-    mocks: Vec<Mock>,
+/// A filter that counts each request as in flight until it's answered.
+fn with_in_flight(
+    in_flight: Arc<InFlight>,
+) -> impl Filter<Extract = (InFlightGuard,), Error = Infallible> + Clone {
+    warp::any().map(move || in_flight.enter())
 }
 
-/// A filter to pass a clone of the configuration to each request.
-fn with_config(config: Config) -> impl Filter<Extract = (Config,), Error = Infallible> + Clone {
-    warp::any().map(move || config.clone())
+/// A filter to pass a handle to the exchange observer to each request.
+fn with_observer(
+    observer: Arc<Observer>,
+) -> impl Filter<Extract = (Arc<Observer>,), Error = Infallible> + Clone {
+    warp::any().map(move || observer.clone())
 }
 
-/// A filter to pass a clone the vector of mocks to each request.
+/// A filter to pass a handle to the mock store to each request.
 fn with_mocks(
-    mocks: Option<Vec<Mock>>,
-) -> impl Filter<Extract = (Option<Vec<Mock>>,), Error = Infallible> + Clone {
+    mocks: Arc<MockStore>,
+) -> impl Filter<Extract = (Arc<MockStore>,), Error = Infallible> + Clone {
     warp::any().map(move || mocks.clone())
 }
 
@@ -123,85 +422,364 @@ fn with_client(client: Client) -> impl Filter<Extract = (Client,), Error = Infal
     warp::any().map(move || client.clone())
 }
 
-/// Loads the body content from a file only if the `body_value` ends with .json, .txt, or .html.
-/// Otherwise returns the literal `body_value`.
-fn load_body_content(body_value: &str) -> String {
-    use log::error;
-    use std::fs;
-    use std::path::Path;
-
-    // Convert &str to `Path` so we can check the extension.
-    let path = Path::new(body_value);
-    let extension = path.extension().and_then(|ext| ext.to_str());
-
-    // Check for recognized extensions
-    match extension {
-        Some("json") | Some("txt") | Some("html") => {
-            // Attempt to read the file. If it fails, log and return the original string.
-            match fs::read_to_string(path) {
-                Ok(contents) => contents,
-                Err(e) => {
-                    error!("Error reading {}: {}", body_value, e);
-                    body_value.to_string()
-                }
+/// A filter to pass a handle to the response cache, if enabled.
+fn with_cache(
+    cache: Option<Arc<ResponseCache>>,
+) -> impl Filter<Extract = (Option<Arc<ResponseCache>>,), Error = Infallible> + Clone {
+    warp::any().map(move || cache.clone())
+}
+
+/// A filter to pass a handle to the shadow target, if configured.
+fn with_shadow(
+    shadow: Option<Arc<Shadow>>,
+) -> impl Filter<Extract = (Option<Arc<Shadow>>,), Error = Infallible> + Clone {
+    warp::any().map(move || shadow.clone())
+}
+
+/// A filter to pass the traffic split rules to each request.
+fn with_splits(
+    splits: Arc<Vec<SplitRule>>,
+) -> impl Filter<Extract = (Arc<Vec<SplitRule>>,), Error = Infallible> + Clone {
+    warp::any().map(move || splits.clone())
+}
+
+/// A filter to pass the rewrite rules to each request.
+fn with_rewrites(
+    rules: Arc<RewriteRules>,
+) -> impl Filter<Extract = (Arc<RewriteRules>,), Error = Infallible> + Clone {
+    warp::any().map(move || rules.clone())
+}
+
+/// A filter to pass the CORS policies to each request.
+fn with_cors(cors: Arc<Cors>) -> impl Filter<Extract = (Arc<Cors>,), Error = Infallible> + Clone {
+    warp::any().map(move || cors.clone())
+}
+
+/// A filter to pass the static directories to each request.
+fn with_static_dirs(
+    dirs: Arc<Vec<StaticDir>>,
+) -> impl Filter<Extract = (Arc<Vec<StaticDir>>,), Error = Infallible> + Clone {
+    warp::any().map(move || dirs.clone())
+}
+
+/// Adds the `--extra-header` values to a response.
+fn add_extra_headers(extra_headers: &[String], resp_headers: &mut warp::http::HeaderMap) {
+    for header in extra_headers.iter() {
+        if let Some((name, value)) = header.split_once(":") {
+            if let (Ok(header_name), Ok(header_value)) = (
+                warp::http::header::HeaderName::from_bytes(name.trim().as_bytes()),
+                warp::http::HeaderValue::from_str(value.trim()),
+            ) {
+                resp_headers.insert(header_name, header_value);
+            } else {
+                warn!("Invalid extra header format: {}", header);
             }
+        } else {
+            warn!("Extra header not in 'Key: Value' format: {}", header);
         }
-        // If extension not recognized or missing, return just the literal
-        _ => body_value.to_string(),
     }
 }
 
+/// The socket address to listen on for a URL like `http://localhost:6969`.
+/// A host name is resolved, and the first address it resolves to is used.
+fn listen_addr(url: &str) -> Result<SocketAddr, String> {
+    let url_parsed = Url::parse(url).map_err(|e| format!("Invalid URL {:?}: {}", url, e))?;
+    let port = url_parsed.port_or_known_default().unwrap_or(6969);
+    // Use the provided hostname if available (with "localhost" mapped to 127.0.0.1), otherwise default.
+    let addr = match url_parsed.host() {
+        Some(url::Host::Domain("localhost")) => SocketAddr::from(([127, 0, 0, 1], port)),
+        Some(url::Host::Ipv4(ip)) => SocketAddr::from((ip, port)),
+        Some(url::Host::Ipv6(ip)) => SocketAddr::from((ip, port)),
+        Some(url::Host::Domain(host)) => (host, port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Cannot resolve {} in {:?}: {}", host, url, e))?
+            .next()
+            .ok_or_else(|| format!("{} in {:?} resolves to no address", host, url))?,
+        None => SocketAddr::from(([0, 0, 0, 0], port)),
+    };
+    Ok(addr)
+}
+
+/// Matches a path against a pattern where `*` stands for any sequence of characters.
+fn path_matches(pattern: &str, path: &str) -> bool {
+    glob::Pattern::new(pattern)
+        .map(|p| p.matches(path))
+        .unwrap_or_else(|_| pattern == path)
+}
+
 #[tokio::main]
 async fn main() {
-    // Initialize logging with a default level so logs are always visible.
-    env_logger::Builder::new()
-        .filter_level(log::LevelFilter::Info)
-        .init();
-
-    // Parse command-line arguments.
-    let config = Config::parse();
-    info!("Starting proxy with config: {:?}", config);
-
-    // If a --mock-config path is provided, parse that file.
-    let optional_mocks = if let Some(ref path) = config.mock_config {
-        match fs::read_to_string(path) {
-            Ok(contents) => match toml::from_str::<MockFile>(&contents) {
-                Ok(parsed) => {
-                    info!("Loaded {} mock(s) from {}", parsed.mocks.len(), path);
-                    Some(parsed.mocks)
+    // Parse command-line arguments, then fill the gaps from the config file.
+    let mut config = Config::parse();
+    logging::init(config.log_format);
+    if let Some(Command::Mocks {
+        action: MocksCommand::Validate { ref files },
+    }) = config.command
+    {
+        std::process::exit(validate::run(files));
+    }
+    let mut problems = Problems::default();
+    let config_file = match config_file::discover(config.config.as_deref()) {
+        Some(path) => match config_file::read(&path, config.profile.as_deref()) {
+            Ok((file, value)) => match file.options_for(config.profile.as_deref()) {
+                Ok(options) => {
+                    let base_dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+                    options.apply_to(&mut config, &base_dir);
+                    config.config = Some(path.display().to_string());
+                    Some((path, value))
                 }
                 Err(err) => {
-                    error!("Failed to parse mock config ({}): {}", path, err);
+                    problems.push(
+                        Category::Options,
+                        format!("Invalid --profile in {}: {}", path.display(), err),
+                    );
                     None
                 }
             },
             Err(err) => {
-                error!("Failed to read mock config file {}: {}", path, err);
+                problems.push(
+                    Category::Options,
+                    format!("Failed to load config file {}: {}", path.display(), err),
+                );
                 None
             }
+        },
+        None => {
+            if config.profile.is_some() {
+                problems.push(
+                    Category::Options,
+                    format!(
+                        "--profile needs a config file: pass --config or add a {}",
+                        config_file::DEFAULT_CONFIG_FILE
+                    ),
+                );
+            }
+            None
+        }
+    };
+
+    // Check the options before anything starts.
+    for (value, name) in [
+        (&config.target_url, "target_url"),
+        (&config.api_url, "api_url"),
+        (&config.shadow_url, "shadow_url"),
+        (&config.admin_url, "admin_url"),
+    ] {
+        match value {
+            Some(url) => {
+                if let Err(err) = startup::check_url(name, url) {
+                    problems.push(Category::Options, err);
+                } else if name == "api_url" || name == "admin_url" {
+                    // These are listened on, so their host must resolve to an address.
+                    if let Err(err) = listen_addr(url) {
+                        problems.push(Category::Options, format!("Invalid {}: {}", name, err));
+                    }
+                }
+            }
+            None if name == "target_url" || name == "api_url" => problems.push(
+                Category::Options,
+                format!(
+                    "Missing {}: pass --{} or set it in {}",
+                    name,
+                    name.replace('_', "-"),
+                    config_file::DEFAULT_CONFIG_FILE
+                ),
+            ),
+            None => {}
+        }
+    }
+    for header in &config.extra_headers {
+        if let Err(err) = startup::check_extra_header(header) {
+            problems.push(Category::Options, err);
+        }
+    }
+    for (value, name) in [
+        (&config.save_request_directory, "save request directory"),
+        (&config.cache_dir, "cache directory"),
+    ] {
+        if let Some(dir) = value {
+            if let Err(err) = startup::ensure_writable_dir(name, dir) {
+                problems.push(Category::Filesystem, err);
+            }
+        }
+    }
+    if let Some(ref file) = config.shadow_diff_file {
+        if let Err(err) = startup::ensure_writable_file("shadow diff file", file) {
+            problems.push(Category::Filesystem, err);
+        }
+    }
+    if let Some(dir) = config
+        .coverage_report
+        .as_deref()
+        .and_then(|file| Path::new(file).parent())
+        .filter(|dir| !dir.as_os_str().is_empty())
+    {
+        if let Err(err) =
+            startup::ensure_writable_dir("coverage report directory", &dir.to_string_lossy())
+        {
+            problems.push(Category::Filesystem, err);
+        }
+    }
+    let access_log = config
+        .access_log
+        .as_deref()
+        .and_then(|file| open_access_log(file, &config, &mut problems));
+    info!(
+        "Starting proxy with config:\n{}",
+        config.to_string().trim_end()
+    );
+
+    // Load the mocks inlined in the config file and the --mock-config files
+    // into one set of mocks and rules.
+    let mock_file = mock_config::load(
+        config_file
+            .as_ref()
+            .map(|(path, value)| (path.as_path(), value)),
+        &config.mock_config,
+        &mut problems,
+    );
+    let cache_rules = mock_file.cache.clone();
+    let splits = mock_file.splits.clone();
+    for rule in &splits {
+        for target in &rule.targets {
+            let name = format!("split target {} URL", target.name);
+            if let Err(err) = startup::check_url(&name, &target.url) {
+                problems.push(Category::Mocks, err);
+            }
         }
+        let total = rule
+            .targets
+            .iter()
+            .map(|t| t.weight as u64)
+            .sum::<u64>()
+            .max(1);
+        let targets: Vec<String> = rule
+            .targets
+            .iter()
+            .map(|t| format!("{} ({}%)", t.name, t.weight as u64 * 100 / total))
+            .collect();
+        info!("Splitting {} between {}", rule.path, targets.join(", "));
+    }
+    let rewrites = RewriteRules {
+        request_headers: mock_file.request_headers.clone(),
+        response_headers: mock_file.response_headers.clone(),
+        request_body: mock_file.request_body.clone(),
+        response_body: mock_file.response_body.clone(),
+    };
+    let cors = Cors::new(mock_file.cors.clone(), config.add_cors_headers);
+    if cors.is_enabled() {
+        info!(
+            "Handling CORS with {} route rule(s){}",
+            cors.rules.len(),
+            if config.add_cors_headers {
+                " and a permissive fallback"
+            } else {
+                ""
+            }
+        );
+    }
+    let mocks = Arc::new(MockStore::new(mock_file.mocks));
+    if let Some(ref dir) = config.mock_dir {
+        let dir = PathBuf::from(dir);
+        if !dir.is_dir() {
+            problems.push(
+                Category::Filesystem,
+                format!("Invalid --mock-dir: {} is not a directory", dir.display()),
+            );
+        }
+        let dir_mocks = mock_dir::load(&dir);
+        info!("Loaded {} mock(s) from {}", dir_mocks.len(), dir.display());
+        mocks.replace_source(MockSource::Directory, dir_mocks);
+        mock_dir::watch(dir, mocks.clone());
+    }
+
+    // Check the static directories up front, so typos don't silently fall through.
+    let mut static_dirs = Vec::new();
+    for spec in &config.static_dirs {
+        match StaticDir::parse(spec) {
+            Ok(dir) => {
+                info!("Serving {} from {}", dir.prefix, dir.dir.display());
+                static_dirs.push(dir);
+            }
+            Err(err) => problems.push(
+                Category::Filesystem,
+                format!("Invalid --static-dir: {}", err),
+            ),
+        }
+    }
+
+    // Report every problem at once, and stop unless --lenient.
+    problems.finish(config.lenient);
+
+    // Set up the response cache if requested.
+    let cache = if config.cache {
+        let cache_ttl = config.cache_ttl.unwrap_or(60);
+        info!(
+            "Caching upstream responses (default TTL {}s, {} route rule(s))",
+            cache_ttl,
+            cache_rules.len()
+        );
+        Some(Arc::new(ResponseCache::new(
+            cache_ttl,
+            cache_rules,
+            config.cache_key_headers.clone(),
+            config.cache_dir.clone(),
+        )))
     } else {
         None
     };
 
     // Parse the API URL (where we will listen) to determine the host and port.
-    let api_url_parsed = Url::parse(&config.api_url)
-        .expect("Invalid api-url. Must be a valid URL like http://localhost:6969");
-    let port = api_url_parsed.port_or_known_default().unwrap_or(6969);
-    // Use the provided hostname if available (with "localhost" mapped to 127.0.0.1), otherwise default.
-    let host = match api_url_parsed.host_str() {
-        Some("localhost") => "127.0.0.1".to_string(),
-        Some(h) => h.to_string(),
-        None => "0.0.0.0".to_string(),
+    let socket_addr = match listen_addr(config.api_url.as_deref().unwrap_or_default()) {
+        Ok(addr) => addr,
+        Err(err) => {
+            error!("Cannot listen on api_url: {}", err);
+            std::process::exit(Category::Options.exit_code());
+        }
     };
-    let socket_addr: SocketAddr = format!("{}:{}", host, port)
-        .parse()
-        .expect("Unable to parse socket address");
     info!("Proxy server listening on {}", socket_addr);
 
     // Construct a Reqwest client.
     let client = Client::new();
 
+    // Requests being answered or mirrored, which shutdown waits for.
+    let in_flight = Arc::new(InFlight::default());
+
+    // Set up traffic mirroring if a shadow target is provided.
+    let shadow = config.shadow_url.as_ref().map(|url| {
+        info!("Mirroring forwarded requests to shadow target {}", url);
+        Arc::new(Shadow::new(
+            url.clone(),
+            config.shadow_compare_headers.clone(),
+            config.shadow_ignore_paths.clone(),
+            config.shadow_diff_file.clone(),
+            client.clone(),
+            in_flight.clone(),
+        ))
+    });
+
+    // The configuration is shared, so the admin API can change it at runtime.
+    let admin_url = config.admin_url.clone();
+    let coverage_report = config.coverage_report.clone();
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout.unwrap_or(10));
+    let journal = Arc::new(Journal::new(if admin_url.is_some() {
+        config.journal_size.unwrap_or(500)
+    } else {
+        0
+    }));
+    let observer = Arc::new(Observer::new(journal.clone(), access_log));
+    let config = Arc::new(RwLock::new(config));
+    if let Some(ref admin_url) = admin_url {
+        let admin = Arc::new(Admin::new(config.clone(), mocks.clone(), journal.clone()));
+        match listen_addr(admin_url) {
+            Ok(admin_addr) => {
+                info!("Admin API listening on {}", admin_addr);
+                tokio::spawn(warp::serve(admin::routes(admin)).run(admin_addr));
+            }
+            Err(err) => error!("Not starting the admin API: {}", err),
+        }
+    }
+
     // Set up a warp filter that captures:
     //   • the HTTP method,
     //   • a clone of all headers,
@@ -210,6 +788,7 @@ async fn main() {
     //   • the full body as bytes,
     //   • plus our configuration, mocks, and Reqwest client.
     let route = warp::any()
+        .and(with_connection())
         .and(warp::method())
         .and(warp::header::headers_cloned())
         .and(warp::path::full())
@@ -219,24 +798,101 @@ async fn main() {
         )
         .and(warp::body::bytes())
         .and(with_config(config))
-        .and(with_mocks(optional_mocks))
+        .and(with_mocks(mocks.clone()))
         .and(with_client(client))
+        .and(with_cache(cache))
+        .and(with_shadow(shadow))
+        .and(with_splits(Arc::new(splits)))
+        .and(with_rewrites(Arc::new(rewrites)))
+        .and(with_cors(Arc::new(cors)))
+        .and(with_static_dirs(Arc::new(static_dirs)))
+        .and(with_observer(observer))
         .and_then(proxy_handler);
+    // Each request counts as in flight until its reply is ready.
+    let route = with_in_flight(in_flight.clone())
+        .and(route)
+        .map(|_in_flight: InFlightGuard, reply| reply);
+
+    // Serve the route through hyper, which warp's own server wraps: warp has
+    // no filter for the request's HTTP version, so it's handed to the route
+    // as a request extension, along with the client's address.
+    let service = warp::service(route);
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let remote = conn.remote_addr();
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req: hyper::Request<hyper::Body>| {
+                let version = req.version();
+                req.extensions_mut().insert(version);
+                req.extensions_mut().insert(remote);
+                service.clone().call(req)
+            }))
+        }
+    });
+    let mut incoming = match AddrIncoming::bind(&socket_addr) {
+        Ok(incoming) => incoming,
+        Err(err) => {
+            error!("Cannot listen on {}: {}", socket_addr, err);
+            std::process::exit(Category::Options.exit_code());
+        }
+    };
+    incoming.set_nodelay(true);
+
+    // Run the server until Ctrl-C or SIGTERM, then stop accepting connections
+    // and let the requests in flight finish.
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = hyper::Server::builder(incoming)
+        .serve(make_service)
+        .with_graceful_shutdown(async {
+            stop_rx.await.ok();
+        });
+    let mut server = tokio::spawn(async {
+        if let Err(err) = server.await {
+            error!("Server error: {}", err);
+        }
+    });
+    tokio::select! {
+        _ = &mut server => {}
+        _ = shutdown::signal() => {
+            let _ = stop_tx.send(());
+            let server = async {
+                server.await.ok();
+            };
+            shutdown::drain(server, &in_flight, shutdown_timeout, shutdown::signal()).await;
+        }
+    }
+
+    // Wait for a recording being written, so no mock file is left half-written.
+    drop(RECORDING.lock().unwrap_or_else(|e| e.into_inner()));
 
-    // Run the server.
-    warp::serve(route).run(socket_addr).await;
+    if !mocks.is_empty() || coverage_report.is_some() {
+        let report = coverage::Report::new(&mocks);
+        info!("{}", report.summary());
+        if let Some(ref base) = coverage_report {
+            report.write(base);
+        }
+    }
 }
 
 /// The handler that proxies every request (or returns a mock).
+#[allow(clippy::too_many_arguments)]
 async fn proxy_handler(
+    (remote, version): (Option<SocketAddr>, Version),
     method: warp::http::Method,
     headers: warp::http::HeaderMap,
     full_path: warp::path::FullPath,
     query: String,
     body: Bytes,
     config: Config,
-    mocks: Option<Vec<Mock>>,
+    mocks: Arc<MockStore>,
     client: Client,
+    cache: Option<Arc<ResponseCache>>,
+    shadow: Option<Arc<Shadow>>,
+    splits: Arc<Vec<SplitRule>>,
+    rewrites: Arc<RewriteRules>,
+    cors: Arc<Cors>,
+    static_dirs: Arc<Vec<StaticDir>>,
+    observer: Arc<Observer>,
 ) -> Result<impl warp::Reply, Infallible> {
     // Fancy logging: display the HTTP verb (in bold blue) and complete request URL (in bold yellow)
     let complete_url = if query.is_empty() {
@@ -244,7 +900,7 @@ async fn proxy_handler(
     } else {
         format!("{}?{}", full_path.as_str(), query)
     };
-    info!(
+    info!(target: logging::REQUEST,
         "{} {}",
         "Incoming request:".bold().green(),
         format!(
@@ -254,20 +910,70 @@ async fn proxy_handler(
         )
     );
 
+    // Note the request; it's observed with its response below.
+    let mut pending = observer.start(
+        remote,
+        &method,
+        version,
+        full_path.as_str(),
+        &query,
+        &headers,
+        &body,
+    );
+
     // Make a clone of the body for forwarding
     let body_for_forwarding = body.clone();
 
+    // 0) PURGE requests drop matching entries from the response cache.
+    if method.as_str() == "PURGE" {
+        if let Some(ref cache) = cache {
+            let purged = cache.purge(full_path.as_str());
+            info!(target: logging::REQUEST,
+                "Purged {} cached response(s) for {}",
+                purged,
+                full_path.as_str()
+            );
+            let reply = warp::http::Response::builder()
+                .status(warp::http::StatusCode::OK)
+                .header("content-type", "application/json")
+                .body(Bytes::from(
+                    serde_json::json!({ "purged": purged }).to_string(),
+                ))
+                .expect("failed to build purge response");
+            return Ok(observer.finish(pending, Outcome::Purge, reply));
+        }
+    }
+
+    // 0) CORS preflights are answered here rather than forwarded.
+    if let Some(reply) = cors.preflight(&method, full_path.as_str(), &headers) {
+        return Ok(observer.finish(pending, Outcome::Preflight, reply));
+    }
+
     // 1) Check if we have a matching mock.
-    if let Some(ref mock_list) = mocks {
-        if let Some(matched) = mock_list.iter().find(|m| {
-            m.method.eq_ignore_ascii_case(method.as_str())
-                && m.path.eq_ignore_ascii_case(full_path.as_str())
-        }) {
+    {
+        if let Some(matched) = mocks.find(method.as_str(), full_path.as_str()) {
             // If matched, return the mock response immediately, no forwarding.
-            info!(
+            info!(target: logging::REQUEST,
                 "Matched mock for method {} and path {}",
                 matched.method, matched.path
             );
+            let outcome = Outcome::Mock {
+                mock_id: matched.id,
+                mock_path: matched.path.clone(),
+            };
+
+            let response_body = match matched.load_body() {
+                Ok(body) => body,
+                Err(err) => {
+                    error!("{}", err);
+                    let reply = warp::http::Response::builder()
+                        .status(warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+                        .header("content-type", "text/plain")
+                        .body(Bytes::from(err))
+                        .expect("failed to build error response");
+                    return Ok(observer.finish(pending, outcome, reply));
+                }
+            };
 
             // Build a mock response with the given status, body, and headers.
             let mut builder = warp::http::Response::builder().status(matched.status);
@@ -275,33 +981,36 @@ async fn proxy_handler(
             for (k, v) in &matched.headers {
                 builder = builder.header(k, v);
             }
-            // If user set --add-cors-headers, add them as well
-            if config.add_cors_headers {
-                builder = builder
-                    .header("Access-Control-Allow-Origin", "*")
-                    .header(
-                        "Access-Control-Allow-Methods",
-                        "GET, POST, PUT, DELETE, OPTIONS",
-                    )
-                    .header(
-                        "Access-Control-Allow-Headers",
-                        "Content-Type, Authorization",
-                    );
-                if !matched.headers.contains_key("Content-Type") {
-                    builder = builder.header("Content-Type", "application/json");
-                }
+            // Add the CORS headers for this route, if any
+            if let Some(mock_headers) = builder.headers_mut() {
+                cors.apply(full_path.as_str(), &headers, mock_headers);
             }
-            // Add extra headers from the CLI
-            for h in &config.extra_headers {
-                if let Some((name, value)) = h.split_once(":") {
-                    let (name, value) = (name.trim(), value.trim());
-                    builder = builder.header(name, value);
+            // Label the body ourselves if the mock doesn't say what it is
+            if !matched
+                .headers
+                .keys()
+                .any(|k| k.eq_ignore_ascii_case("Content-Type"))
+            {
+                if let Some(content_type) =
+                    content_type::sniff(matched.body_file_name(), &response_body)
+                {
+                    builder = builder.header("Content-Type", content_type);
                 }
             }
-            let response_body = Bytes::from(load_body_content(&matched.body));
+            // Add extra headers from the CLI, then apply the response header rules
+            // last, so they can override anything above
+            if let Some(mock_headers) = builder.headers_mut() {
+                add_extra_headers(&config.extra_headers, mock_headers);
+                header_rules::apply(
+                    &rewrites.response_headers,
+                    &method,
+                    full_path.as_str(),
+                    mock_headers,
+                );
+            }
 
             // Log the mock response size
-            info!("Mock response status: {}", matched.status);
+            info!(target: logging::REQUEST, "Mock response status: {}", matched.status);
 
             // Save response if save directory is specified
             if let Some(save_dir) = &config.save_request_directory {
@@ -310,81 +1019,326 @@ async fn proxy_handler(
                     &method,
                     &full_path,
                     &query,
+                    &body,
                     &String::from_utf8_lossy(&response_body),
+                    None,
                 );
             }
 
-            let response = builder
-                .body(response_body)
-                .expect("failed to build mock response");
-            return Ok(response);
+            // Mocks are checked when they're loaded, but a bad one must not
+            // take the proxy down with it.
+            let response = match builder.body(response_body) {
+                Ok(response) => response,
+                Err(err) => {
+                    let message =
+                        format!("Invalid mock {} {}: {}", matched.method, matched.path, err);
+                    error!("{}", message);
+                    warp::http::Response::builder()
+                        .status(warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+                        .header("content-type", "text/plain")
+                        .body(Bytes::from(message))
+                        .expect("failed to build error response")
+                }
+            };
+            return Ok(observer.finish(pending, outcome, response));
+        }
+    }
+
+    // Tell why the mocks closest to this request didn't answer it, in the log
+    // and in a debug header on the response.
+    let near_misses = mocks.near_misses(method.as_str(), full_path.as_str());
+    if !near_misses.is_empty() {
+        info!(target: logging::REQUEST,
+            "No mock matched {} {}. Closest mocks:",
+            method,
+            full_path.as_str()
+        );
+        for (i, near_miss) in near_misses.iter().enumerate() {
+            info!(target: logging::REQUEST, "  {}. {}", i + 1, near_miss.describe());
+        }
+    }
+    let near_miss_header = near_miss::header_value(&near_misses);
+
+    // 2) No mock matched -> Serve the file if a static directory has it.
+    if let Some(file) = static_files::find(&static_dirs, &method, full_path.as_str()) {
+        let mut response = static_files::serve(&file, &method, &headers);
+        if let Some(value) = near_miss_header {
+            response.headers_mut().insert("x-proxxyy-near-miss", value);
         }
+        cors.apply(full_path.as_str(), &headers, response.headers_mut());
+        add_extra_headers(&config.extra_headers, response.headers_mut());
+        header_rules::apply(
+            &rewrites.response_headers,
+            &method,
+            full_path.as_str(),
+            response.headers_mut(),
+        );
+        info!(target: logging::REQUEST, "Static response status: {}", response.status());
+        return Ok(observer.finish(pending, Outcome::Static, response));
     }
 
-    // 2) No mock matched -> Forward to real target.
-    let target_url = config.target_url.trim_end_matches('/');
+    // 3) Nothing local -> Forward to real target (or to the one picked by a split rule).
+    // The endpoint is one the mocks don't cover yet.
+    mocks.note_unmatched(method.as_str(), full_path.as_str());
+    let split_target = split::choose_target(&splits, &method, full_path.as_str(), &headers);
+    let target_url = split_target
+        .map_or(config.target_url.as_deref().unwrap_or_default(), |t| {
+            t.url.as_str()
+        })
+        .trim_end_matches('/');
     let mut new_url = format!("{}{}", target_url, full_path.as_str());
     if !query.is_empty() {
         new_url = format!("{}?{}", new_url, query);
     }
-    info!(
-        "No mock files provided. Forwarding request to the target URL: {}",
-        new_url
-    );
-
-    // Create a new request to forward to the target using Reqwest.
-    let mut req_builder = client.request(method.clone(), &new_url);
+    if let Some(target) = split_target {
+        info!(target: logging::REQUEST,
+            "Forwarding request to split target {}: {}",
+            target.name.bold(),
+            new_url
+        );
+    } else if mocks.is_empty() {
+        info!(target: logging::REQUEST,
+            "No mock files provided. Forwarding request to the target URL: {}",
+            new_url
+        );
+    } else {
+        info!(target: logging::REQUEST,
+            "No mock matched ({} loaded). Forwarding request to the target URL: {}",
+            mocks.len(),
+            new_url
+        );
+    }
 
-    // Copy every header from the incoming request except the "host" header.
-    for (name, value) in headers.iter() {
-        if name.as_str().to_lowercase() == "host" {
-            continue;
+    // Apply the request header rules before anything goes upstream.
+    let mut upstream_headers = headers.clone();
+    header_rules::apply(
+        &rewrites.request_headers,
+        &method,
+        full_path.as_str(),
+        &mut upstream_headers,
+    );
+    // Run the request body rules, then fix up Content-Length to match.
+    let request_content_type = upstream_headers
+        .get(warp::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let body_for_forwarding = body_rules::apply(
+        &rewrites.request_body,
+        &method,
+        full_path.as_str(),
+        request_content_type.as_deref(),
+        body_for_forwarding,
+    );
+    if body_for_forwarding != body {
+        // The journal keeps the body as forwarded, like the recording does.
+        pending.forwarded_body(&body_for_forwarding);
+        if upstream_headers.contains_key(warp::http::header::CONTENT_LENGTH) {
+            upstream_headers.insert(
+                warp::http::header::CONTENT_LENGTH,
+                warp::http::HeaderValue::from(body_for_forwarding.len()),
+            );
+        }
+        if config.show_body {
+            info!(target: logging::REQUEST,
+                "Rewritten request body: {}",
+                String::from_utf8_lossy(&body_for_forwarding)
+            );
+        } else {
+            info!(target: logging::REQUEST,
+                "Rewritten request body: [hidden] ({} bytes)",
+                body_for_forwarding.len()
+            );
         }
-        req_builder = req_builder.header(name, value);
     }
 
-    // Include the body if available.
-    if !body_for_forwarding.is_empty() {
-        req_builder = req_builder.body(body_for_forwarding);
+    // Body rules need an uncompressed body to work on.
+    if rewrites
+        .response_body
+        .iter()
+        .any(|r| r.matches_route(&method, full_path.as_str()))
+    {
+        upstream_headers.remove(warp::http::header::ACCEPT_ENCODING);
     }
 
-    // Send the request.
-    let proxied_response = match req_builder.send().await {
-        Ok(resp) => resp,
-        Err(err) => {
-            error!("Error forwarding request: {}", err);
-            let reply = warp::http::Response::builder()
-                .status(warp::http::StatusCode::BAD_GATEWAY)
-                .header("content-type", "text/plain")
-                .body(Bytes::from(format!("Error forwarding request: {}", err)))
-                .expect("failed to build error response");
-            return Ok(reply);
-        }
+    // Look the request up in the response cache, if enabled.
+    // Split routes are cached per target, so targets never leak into each other.
+    let cache_key = cache
+        .as_ref()
+        .and_then(|c| c.key_for(&method, full_path.as_str(), &query, &headers))
+        .map(|key| match split_target {
+            Some(target) => format!("{}@{}", key, target.name),
+            None => key,
+        });
+    let client_bypasses_cache = cache::cache_control(&headers)
+        .iter()
+        .any(|(name, _)| name == "no-cache" || name == "no-store");
+    let lookup = match (&cache, &cache_key) {
+        (Some(cache), Some(key)) if !client_bypasses_cache => cache.lookup(key, &headers),
+        _ => Lookup::Miss,
     };
 
-    // Retrieve the response status and headers.
-    let status = proxied_response.status();
-    let mut resp_headers = warp::http::HeaderMap::new();
-    for (name, value) in proxied_response.headers().iter() {
-        resp_headers.insert(name, value.clone());
-    }
+    let mut upstream_elapsed = None;
+    let (status, mut resp_headers, resp_body, cache_status) = if let Lookup::Fresh(entry) = lookup {
+        info!(target: logging::REQUEST, "Serving cached response for {}", complete_url);
+        let (status, headers) = cached_status_and_headers(&entry);
+        (status, headers, Bytes::from(entry.body), "HIT")
+    } else {
+        // Create a new request to forward to the target using Reqwest.
+        let mut req_builder = client.request(method.clone(), &new_url);
 
-    // Get the response body as bytes.
-    let resp_body = match proxied_response.bytes().await {
-        Ok(b) => b,
-        Err(err) => {
-            error!("Error reading response body: {}", err);
-            let reply = warp::http::Response::builder()
-                .status(warp::http::StatusCode::BAD_GATEWAY)
-                .header("content-type", "text/plain")
-                .body(Bytes::from(format!("Error reading response body: {}", err)))
-                .expect("failed to build error response");
-            return Ok(reply);
+        // Copy every header from the incoming request except the "host" header.
+        for (name, value) in upstream_headers.iter() {
+            if name.as_str().to_lowercase() == "host" {
+                continue;
+            }
+            req_builder = req_builder.header(name, value);
+        }
+
+        // Include the body if available.
+        if !body_for_forwarding.is_empty() {
+            req_builder = req_builder.body(body_for_forwarding.clone());
+        }
+
+        // Revalidate a stale cache entry unless the client sent its own conditions.
+        let client_is_conditional = headers.contains_key(warp::http::header::IF_NONE_MATCH)
+            || headers.contains_key(warp::http::header::IF_MODIFIED_SINCE);
+        let stale = match lookup {
+            Lookup::Stale(entry) if !client_is_conditional => Some(entry),
+            _ => None,
+        };
+        // Keep an unconditional copy of the request, in case the entry is gone
+        // by the time the upstream confirms it.
+        let mut unconditional = None;
+        if let Some(ref entry) = stale {
+            unconditional = req_builder.try_clone();
+            if let Some(etag) = entry.header("etag") {
+                req_builder = req_builder.header(warp::http::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = entry.header("last-modified") {
+                req_builder =
+                    req_builder.header(warp::http::header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        // Send the request.
+        let mut fetched = match fetch_upstream(req_builder, target_url).await {
+            Ok(fetched) => fetched,
+            Err(message) => {
+                return Ok(observer.finish(
+                    pending,
+                    Outcome::UpstreamError { url: new_url },
+                    bad_gateway(message),
+                ));
+            }
+        };
+
+        // The upstream confirmed our stale copy is still valid.
+        let mut revalidated = None;
+        if let (Some(cache), Some(key), Some(_)) = (&cache, &cache_key, &stale) {
+            if fetched.status == warp::http::StatusCode::NOT_MODIFIED {
+                let ttl = cache
+                    .ttl_for_response(&method, full_path.as_str(), &headers, 200, &fetched.headers)
+                    .unwrap_or(0);
+                revalidated = cache.refresh(key, &fetched.headers, ttl);
+                if revalidated.is_none() {
+                    // The entry was purged or evicted meanwhile. The 304 answers
+                    // our conditions, not the client's, so ask again without them.
+                    warn!(
+                        "Cached response for {} was dropped during revalidation, fetching it again",
+                        complete_url
+                    );
+                    let retry = match unconditional {
+                        Some(req_builder) => fetch_upstream(req_builder, target_url).await,
+                        None => Err("Error forwarding request: cannot repeat request".to_string()),
+                    };
+                    fetched = match retry {
+                        Ok(fetched) => fetched,
+                        Err(message) => {
+                            return Ok(observer.finish(
+                                pending,
+                                Outcome::UpstreamError { url: new_url },
+                                bad_gateway(message),
+                            ));
+                        }
+                    };
+                }
+            }
+        }
+
+        let Fetched {
+            status,
+            headers: resp_headers,
+            body: resp_body,
+            elapsed,
+        } = fetched;
+        upstream_elapsed = Some(elapsed);
+
+        match (&cache, &cache_key, revalidated) {
+            (_, _, Some(entry)) => {
+                info!(target: logging::REQUEST,
+                    "Upstream confirmed cached response for {} is still valid",
+                    complete_url
+                );
+                let (status, headers) = cached_status_and_headers(&entry);
+                (status, headers, Bytes::from(entry.body), "REVALIDATED")
+            }
+            (Some(cache), Some(key), None) => {
+                if let Some(ttl) = cache.ttl_for_response(
+                    &method,
+                    full_path.as_str(),
+                    &headers,
+                    status.as_u16(),
+                    &resp_headers,
+                ) {
+                    cache.store(
+                        key,
+                        full_path.as_str(),
+                        &headers,
+                        status.as_u16(),
+                        &resp_headers,
+                        &resp_body,
+                        ttl,
+                    );
+                }
+                (status, resp_headers, resp_body, "MISS")
+            }
+            _ => (status, resp_headers, resp_body, ""),
         }
     };
 
+    // Mirror the request to the shadow target, unless the target wasn't contacted.
+    if let Some(shadow) = shadow {
+        if cache_status != "HIT" {
+            shadow.mirror(shadow::Exchange {
+                method: method.clone(),
+                path_and_query: complete_url.clone(),
+                headers: upstream_headers.clone(),
+                body: body_for_forwarding.clone(),
+                status,
+                resp_headers: resp_headers.clone(),
+                resp_body: resp_body.clone(),
+            });
+        }
+    }
+
+    // Run the response body rules, fixing up the headers that describe the body.
+    let resp_body = body_rules::apply_to_response(
+        &rewrites.response_body,
+        &method,
+        full_path.as_str(),
+        &mut resp_headers,
+        resp_body,
+    );
+
+    if !cache_status.is_empty() {
+        resp_headers.insert(
+            "x-proxxyy-cache",
+            warp::http::HeaderValue::from_static(cache_status),
+        );
+    }
+
     // Log the response size
-    info!("Response status:  {}", status);
+    info!(target: logging::REQUEST, "Response status:  {}", status);
 
     // Pretty-print the response headers as pretty JSON if not hidden
     if config.show_headers {
@@ -392,12 +1346,12 @@ async fn proxy_handler(
             .iter()
             .map(|(k, v)| (k.as_str(), v.to_str().unwrap_or("")))
             .collect();
-        info!(
+        info!(target: logging::REQUEST,
             "Response headers:\n{}",
             serde_json::to_string_pretty(&headers_map).unwrap()
         );
     } else {
-        info!("Response headers: [hidden] ({} bytes)", resp_headers.len());
+        info!(target: logging::REQUEST, "Response headers: [hidden] ({} bytes)", resp_headers.len());
     }
 
     // print the response body.
@@ -406,15 +1360,15 @@ async fn proxy_handler(
     if config.show_body {
         let response_body_str = String::from_utf8_lossy(&resp_body);
         if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(&response_body_str) {
-            info!(
+            info!(target: logging::REQUEST,
                 "Response body: {}",
                 serde_json::to_string_pretty(&json_value).unwrap()
             );
         } else {
-            info!("Response body: {}", response_body_str);
+            info!(target: logging::REQUEST, "Response body: {}", response_body_str);
         }
     } else {
-        info!("Response body:    [hidden] ({} bytes)", resp_body.len());
+        info!(target: logging::REQUEST, "Response body:    [hidden] ({} bytes)", resp_body.len());
     }
 
     // Save response if save directory is specified
@@ -424,47 +1378,31 @@ async fn proxy_handler(
             &method,
             &full_path,
             &query,
+            &body_for_forwarding,
             &String::from_utf8_lossy(&resp_body),
+            split_target
+                .map(|t| format!("{} ({})", t.name, t.url))
+                .as_deref(),
         );
     }
 
-    // Add extra headers provided by the user.
-    for header in config.extra_headers.iter() {
-        if let Some((name, value)) = header.split_once(":") {
-            if let (Ok(header_name), Ok(header_value)) = (
-                warp::http::header::HeaderName::from_bytes(name.trim().as_bytes()),
-                warp::http::HeaderValue::from_str(value.trim()),
-            ) {
-                resp_headers.insert(header_name, header_value);
-            } else {
-                warn!("Invalid extra header format: {}", header);
-            }
-        } else {
-            warn!("Extra header not in 'Key: Value' format: {}", header);
-        }
+    if let Some(value) = near_miss_header {
+        resp_headers.insert("x-proxxyy-near-miss", value);
     }
 
-    // If the flag is set, add some useful CORS headers.
-    if config.add_cors_headers {
-        resp_headers.insert(
-            warp::http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
-            warp::http::HeaderValue::from_static("*"),
-        );
-        resp_headers.insert(
-            warp::http::header::ACCESS_CONTROL_ALLOW_METHODS,
-            warp::http::HeaderValue::from_static("GET, POST, PUT, DELETE, OPTIONS"),
-        );
-        resp_headers.insert(
-            warp::http::header::ACCESS_CONTROL_ALLOW_HEADERS,
-            warp::http::HeaderValue::from_static("Content-Type, Authorization"),
-        );
-        if !resp_headers.contains_key(warp::http::header::CONTENT_TYPE) {
-            resp_headers.insert(
-                warp::http::header::CONTENT_TYPE,
-                warp::http::HeaderValue::from_static("application/json"),
-            );
-        }
-    }
+    // Add extra headers provided by the user.
+    add_extra_headers(&config.extra_headers, &mut resp_headers);
+
+    // Add the CORS headers for this route, if any.
+    cors.apply(full_path.as_str(), &headers, &mut resp_headers);
+
+    // Apply the response header rules last, so they can override anything above.
+    header_rules::apply(
+        &rewrites.response_headers,
+        &method,
+        full_path.as_str(),
+        &mut resp_headers,
+    );
 
     // Build the final response using the forwarded status, headers, and body.
     let mut response_builder = warp::http::Response::builder().status(status);
@@ -475,17 +1413,115 @@ async fn proxy_handler(
         .body(resp_body)
         .expect("failed to build response");
 
-    Ok(response)
+    let outcome = Outcome::Forwarded {
+        url: new_url,
+        cache: Some(cache_status.to_string()).filter(|s| !s.is_empty()),
+        upstream_ms: upstream_elapsed.map(|d| d.as_secs_f64() * 1000.0),
+    };
+    Ok(observer.finish(pending, outcome, response))
+}
+
+/// An upstream response, read in full.
+struct Fetched {
+    status: warp::http::StatusCode,
+    headers: warp::http::HeaderMap,
+    body: Bytes,
+    elapsed: Duration,
+}
+
+/// Sends a request to the target and reads the whole response, recording the
+/// upstream metrics. On failure, returns the message to answer with.
+async fn fetch_upstream(
+    req_builder: reqwest::RequestBuilder,
+    target_url: &str,
+) -> Result<Fetched, String> {
+    let started = std::time::Instant::now();
+    let response = match req_builder.send().await {
+        Ok(resp) => resp,
+        Err(err) => {
+            error!("Error forwarding request: {}", err);
+            let kind = if err.is_timeout() {
+                "timeout"
+            } else if err.is_connect() {
+                "connect"
+            } else {
+                "request"
+            };
+            metrics::METRICS.upstream_error(target_url, kind);
+            return Err(format!("Error forwarding request: {}", err));
+        }
+    };
+
+    // Retrieve the response status and headers.
+    let status = response.status();
+    let mut headers = warp::http::HeaderMap::new();
+    for (name, value) in response.headers().iter() {
+        headers.insert(name, value.clone());
+    }
+
+    // Get the response body as bytes.
+    let body = match response.bytes().await {
+        Ok(b) => b,
+        Err(err) => {
+            error!("Error reading response body: {}", err);
+            metrics::METRICS.upstream_error(target_url, "body");
+            return Err(format!("Error reading response body: {}", err));
+        }
+    };
+
+    let elapsed = started.elapsed();
+    metrics::METRICS.observe_upstream(target_url, elapsed);
+    Ok(Fetched {
+        status,
+        headers,
+        body,
+        elapsed,
+    })
+}
+
+/// A plain-text 502 answered when the target can't be reached.
+fn bad_gateway(message: String) -> warp::http::Response<Bytes> {
+    warp::http::Response::builder()
+        .status(warp::http::StatusCode::BAD_GATEWAY)
+        .header("content-type", "text/plain")
+        .body(Bytes::from(message))
+        .expect("failed to build error response")
+}
+
+/// Rebuilds the status and headers of a cached response.
+fn cached_status_and_headers(
+    entry: &cache::CachedResponse,
+) -> (warp::http::StatusCode, warp::http::HeaderMap) {
+    let status =
+        warp::http::StatusCode::from_u16(entry.status).unwrap_or(warp::http::StatusCode::OK);
+    let mut headers = warp::http::HeaderMap::new();
+    for (name, value) in &entry.headers {
+        if let (Ok(name), Ok(value)) = (
+            warp::http::header::HeaderName::from_bytes(name.as_bytes()),
+            warp::http::HeaderValue::from_str(value),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    (status, headers)
 }
 
+/// Held while a recording is written, so recordings don't overwrite each
+/// other's `mocked-request.toml` updates and shutdown can wait for them.
+static RECORDING: Mutex<()> = Mutex::new(());
+
 /// Helper function to save response data to a file
 fn save_response_to_file(
     save_dir: &str,
     method: &warp::http::Method,
     full_path: &warp::path::FullPath,
     query: &str,
+    request_body: &[u8],
     response_body: &str,
+    upstream: Option<&str>,
 ) {
+    let _recording = RECORDING.lock().unwrap_or_else(|e| e.into_inner());
+
     // Get current timestamp for unique filenames
     let timestamp = chrono::Utc::now().timestamp();
 
@@ -534,11 +1570,7 @@ fn save_response_to_file(
 
     // Add query parameters to filename (sanitized)
     if !query.is_empty() {
-        filename_base = format!(
-            "{}_{}",
-            filename_base,
-            query.replace('&', "_").replace('=', "_")
-        );
+        filename_base = format!("{}_{}", filename_base, query.replace(['&', '='], "_"));
     }
 
     // Replace special characters with underscores
@@ -547,6 +1579,7 @@ fn save_response_to_file(
     // Create a directory if it doesn't exist
     if let Err(e) = fs::create_dir_all(save_dir) {
         error!("Failed to create save directory {}: {}", save_dir, e);
+        metrics::METRICS.recording_failure();
         return;
     }
 
@@ -576,21 +1609,44 @@ fn save_response_to_file(
             json_path.display(),
             e
         );
+        metrics::METRICS.recording_failure();
         return;
     }
     info!("Saved JSON response to {}", json_path.display());
 
+    // Save the request body (as forwarded, i.e. after any rewrite rules) next to it
+    let request_filename = format!("{}_{}_request.json", filename_base, timestamp);
+    if !request_body.is_empty() {
+        let request_path = Path::new(save_dir).join(&request_filename);
+        if let Err(e) = fs::write(&request_path, request_body) {
+            error!(
+                "Failed to save request body to {}: {}",
+                request_path.display(),
+                e
+            );
+            metrics::METRICS.recording_failure();
+        } else {
+            info!("Saved request body to {}", request_path.display());
+        }
+    }
+
     // 2. Create or update the TOML mock configuration file
     // Relative path to the JSON file from the TOML file's perspective
     let relative_json_path = json_filename;
 
     // Create the TOML content for this mock entry
-    let mock_entry = format!(
+    let mut mock_entry = format!(
         "[[mocks]]\nmethod = \"{}\"\npath = \"{}\"\nstatus = 200\nbody = \"{}\"\n",
-        method.to_string(),
-        complete_uri,
-        relative_json_path
+        method, complete_uri, relative_json_path
     );
+    // Point at the request body that produced this response, if there was one
+    if !request_body.is_empty() {
+        mock_entry = format!("# Request body: {}\n{}", request_filename, mock_entry);
+    }
+    // Note which upstream answered, so recordings of split routes can be told apart
+    if let Some(upstream) = upstream {
+        mock_entry = format!("# Recorded from {}\n{}", upstream, mock_entry);
+    }
 
     // Check if the TOML file already exists
     let toml_content = if toml_path.exists() {
@@ -621,13 +1677,18 @@ fn save_response_to_file(
         )
     };
 
-    // Write the TOML file
-    if let Err(e) = fs::write(&toml_path, toml_content) {
+    // Write the TOML file next to the old one and swap it in, so it's never
+    // left half-written.
+    let temp_path = toml_path.with_extension("toml.tmp");
+    if let Err(e) =
+        fs::write(&temp_path, toml_content).and_then(|_| fs::rename(&temp_path, &toml_path))
+    {
         error!(
             "Failed to save TOML mock config to {}: {}",
             toml_path.display(),
             e
         );
+        metrics::METRICS.recording_failure();
     } else {
         info!("Updated TOML mock config at {}", toml_path.display());
     }
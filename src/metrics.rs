@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The proxy's metrics, served in the Prometheus text format on the admin
+/// API's `GET /metrics`.
+pub static METRICS: Metrics = Metrics::new();
+
+/// Distinct label sets kept per metric; beyond that, new routes are counted
+/// under `route="other"` so a scan of random paths can't exhaust memory.
+const MAX_SERIES: usize = 5000;
+
+/// Upper bounds of the upstream latency histogram buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Labels of `proxxyy_requests_total`: method, route, status, outcome.
+type RequestLabels = (String, String, u16, &'static str);
+
+#[derive(Debug)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<RequestLabels, u64>>,
+    request_bytes: AtomicU64,
+    response_bytes: AtomicU64,
+    upstream_latency: Mutex<BTreeMap<String, Histogram>>,
+    upstream_errors: Mutex<BTreeMap<(String, &'static str), u64>>,
+    recording_failures: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket (not cumulative), then above the last bucket.
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            requests: Mutex::new(BTreeMap::new()),
+            request_bytes: AtomicU64::new(0),
+            response_bytes: AtomicU64::new(0),
+            upstream_latency: Mutex::new(BTreeMap::new()),
+            upstream_errors: Mutex::new(BTreeMap::new()),
+            recording_failures: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts an answered request. `outcome` is the kind of exchange outcome,
+    /// e.g. `mock` or `forwarded`.
+    pub fn observe_request(
+        &self,
+        method: &str,
+        route: &str,
+        status: u16,
+        outcome: &'static str,
+        request_bytes: usize,
+        response_bytes: usize,
+    ) {
+        self.request_bytes
+            .fetch_add(request_bytes as u64, Ordering::Relaxed);
+        self.response_bytes
+            .fetch_add(response_bytes as u64, Ordering::Relaxed);
+
+        let mut requests = self.requests.lock().unwrap();
+        let mut labels = (method.to_string(), route.to_string(), status, outcome);
+        if requests.len() >= MAX_SERIES && !requests.contains_key(&labels) {
+            labels.1 = "other".to_string();
+        }
+        *requests.entry(labels).or_insert(0) += 1;
+    }
+
+    /// Records how long an upstream took to answer, body included.
+    pub fn observe_upstream(&self, upstream: &str, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut latency = self.upstream_latency.lock().unwrap();
+        let histogram = latency.entry(upstream.to_string()).or_default();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(BUCKETS.len());
+        histogram.counts[bucket] += 1;
+        histogram.sum += seconds;
+    }
+
+    /// Counts a request the upstream couldn't answer. `kind` is one of
+    /// `connect`, `timeout`, `request` or `body`.
+    pub fn upstream_error(&self, upstream: &str, kind: &'static str) {
+        *self
+            .upstream_errors
+            .lock()
+            .unwrap()
+            .entry((upstream.to_string(), kind))
+            .or_insert(0) += 1;
+    }
+
+    /// Counts a recording (`--save-request-directory`) that couldn't be written.
+    pub fn recording_failure(&self) {
+        self.recording_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "proxxyy_requests_total",
+            "counter",
+            "Requests answered, by method, route, status and outcome.",
+        );
+        for ((method, route, status, outcome), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "proxxyy_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\",outcome=\"{}\"}} {}",
+                escape(method),
+                escape(route),
+                status,
+                outcome,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "proxxyy_request_bytes_total",
+            "counter",
+            "Bytes of request bodies received.",
+        );
+        let _ = writeln!(
+            out,
+            "proxxyy_request_bytes_total {}",
+            self.request_bytes.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "proxxyy_response_bytes_total",
+            "counter",
+            "Bytes of response bodies sent.",
+        );
+        let _ = writeln!(
+            out,
+            "proxxyy_response_bytes_total {}",
+            self.response_bytes.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "proxxyy_upstream_duration_seconds",
+            "histogram",
+            "Time upstreams took to answer forwarded requests, body included.",
+        );
+        for (upstream, histogram) in self.upstream_latency.lock().unwrap().iter() {
+            let upstream = escape(upstream);
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(&histogram.counts) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "proxxyy_upstream_duration_seconds_bucket{{upstream=\"{}\",le=\"{}\"}} {}",
+                    upstream, bound, cumulative
+                );
+            }
+            cumulative += histogram.counts[BUCKETS.len()];
+            let _ = writeln!(
+                out,
+                "proxxyy_upstream_duration_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}",
+                upstream, cumulative
+            );
+            let _ = writeln!(
+                out,
+                "proxxyy_upstream_duration_seconds_sum{{upstream=\"{}\"}} {}",
+                upstream, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "proxxyy_upstream_duration_seconds_count{{upstream=\"{}\"}} {}",
+                upstream, cumulative
+            );
+        }
+
+        header(
+            &mut out,
+            "proxxyy_upstream_errors_total",
+            "counter",
+            "Forwarded requests the upstream couldn't answer, by kind of error.",
+        );
+        for ((upstream, kind), count) in self.upstream_errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "proxxyy_upstream_errors_total{{upstream=\"{}\",kind=\"{}\"}} {}",
+                escape(upstream),
+                kind,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "proxxyy_recording_failures_total",
+            "counter",
+            "Recordings that couldn't be written to the save request directory.",
+        );
+        let _ = writeln!(
+            out,
+            "proxxyy_recording_failures_total {}",
+            self.recording_failures.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The route label for a request no mock answered: its path, with segments
+/// that look like ids (numbers, UUIDs, long hex strings) replaced by `{id}`.
+pub fn route_of(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            if looks_like_id(segment) {
+                "{id}"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn looks_like_id(segment: &str) -> bool {
+    if segment.is_empty() {
+        return false;
+    }
+    if segment.bytes().all(|b| b.is_ascii_digit()) {
+        return true;
+    }
+    let hex = segment.bytes().filter(|b| b.is_ascii_hexdigit()).count();
+    let dashes = segment.bytes().filter(|b| *b == b'-').count();
+    hex + dashes == segment.len() && hex >= 16 && segment.bytes().any(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collapses_id_segments() {
+        assert_eq!(route_of("/v1/users/42/orders"), "/v1/users/{id}/orders");
+        assert_eq!(
+            route_of("/v1/items/3f2b8c1e-9d4a-4b7e-8a61-0c5d2e9f7a10"),
+            "/v1/items/{id}"
+        );
+        assert_eq!(
+            route_of("/v1/blobs/5e884898da28047151d0e56f"),
+            "/v1/blobs/{id}"
+        );
+        // Words, versions and short or digit-free hex stay as they are.
+        assert_eq!(route_of("/v2/users/me"), "/v2/users/me");
+        assert_eq!(route_of("/v1/colors/beef"), "/v1/colors/beef");
+        assert_eq!(
+            route_of("/v1/tags/deadbeefdeadbeefdead"),
+            "/v1/tags/deadbeefdeadbeefdead"
+        );
+        assert_eq!(route_of("/"), "/");
+    }
+
+    #[test]
+    fn counts_new_routes_as_other_past_the_cap() {
+        let metrics = Metrics::new();
+        for i in 0..MAX_SERIES {
+            metrics.observe_request("GET", &format!("/r{}", i), 200, "forwarded", 0, 0);
+        }
+        metrics.observe_request("GET", "/r0", 200, "forwarded", 0, 0);
+        metrics.observe_request("GET", "/new", 200, "forwarded", 1, 2);
+        metrics.observe_request("GET", "/newer", 200, "forwarded", 0, 0);
+
+        let requests = metrics.requests.lock().unwrap();
+        assert_eq!(requests.len(), MAX_SERIES + 1);
+        let count =
+            |route: &str| requests[&("GET".to_string(), route.to_string(), 200, "forwarded")];
+        assert_eq!(count("/r0"), 2);
+        assert_eq!(count("other"), 2);
+        drop(requests);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            "proxxyy_requests_total{method=\"GET\",route=\"other\",status=\"200\",outcome=\"forwarded\"} 2\n"
+        ));
+        assert!(rendered.contains("proxxyy_response_bytes_total 2\n"));
+    }
+}
@@ -0,0 +1,577 @@
+use crate::near_miss::{self, NearMiss};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use warp::http::header::{HeaderName, HeaderValue};
+use warp::http::StatusCode;
+
+/// A single mock rule (loaded from the config file).
+/// For example, from TOML:
+///
+/// [[mocks]]
+/// method = "GET"
+/// path = "/v1/mock"
+/// status = 200
+/// body = "Mocked body."
+///
+/// [mocks.headers]
+/// X-My-Header = "123"
+///
+/// Instead of `body`, a mock may set one of:
+///
+/// body_file = "fixtures/logo.png"   # any file, served as raw bytes
+/// body_base64 = "iVBORw0KGgo..."     # raw bytes, base64-encoded
+/// body_json = { id = 1, tags = ["a", "b"] }
+///
+/// Path segments written as `{name}` match any single segment,
+/// e.g. `path = "/v1/users/{id}"`.
+///
+/// A mock may also say how often it expects to be hit, checked through the
+/// admin API's `GET /verify`:
+///
+/// times = 1      # exactly once
+/// at_least = 2   # two times or more
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Mock {
+    /// Assigned by the mock store, so mocks can be addressed through the admin API.
+    #[serde(skip_deserializing)]
+    pub id: u64,
+    pub method: String,
+    pub path: String,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub body: String,
+    #[serde(default)]
+    pub body_file: Option<String>,
+    #[serde(default)]
+    pub body_base64: Option<String>,
+    #[serde(default)]
+    pub body_json: Option<serde_json::Value>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub times: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at_least: Option<u64>,
+    /// How many requests the mock has answered.
+    #[serde(skip_deserializing)]
+    pub hits: Hits,
+    /// Where the mock was loaded from.
+    #[serde(skip_deserializing)]
+    pub source: MockSource,
+}
+
+/// A mock's hit counter. It's bumped through a shared reference, so serving a
+/// mock only takes the store's read lock; clones take a snapshot.
+#[derive(Debug, Default)]
+pub struct Hits(AtomicU64);
+
+impl Hits {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn count(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Clone for Hits {
+    fn clone(&self) -> Hits {
+        Hits(AtomicU64::new(self.get()))
+    }
+}
+
+impl Serialize for Hits {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.get())
+    }
+}
+
+/// Where a mock came from, so reloads only replace their own mocks.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MockSource {
+    /// The `--mock-config` file.
+    #[default]
+    Config,
+    /// The `--mock-dir` directory tree.
+    Directory,
+    /// Added at runtime through the admin API.
+    Admin,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl Mock {
+    /// Whether the mock answers this method and path.
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        if !self.method.eq_ignore_ascii_case(method) {
+            return false;
+        }
+        if self.path.eq_ignore_ascii_case(path) {
+            return true;
+        }
+        let template: Vec<&str> = self.path.split('/').collect();
+        let segments: Vec<&str> = path.split('/').collect();
+        template.len() == segments.len()
+            && template.iter().zip(&segments).all(|(t, s)| {
+                (t.starts_with('{') && t.ends_with('}') && !s.is_empty())
+                    || t.eq_ignore_ascii_case(s)
+            })
+    }
+
+    /// Checks the mock once, before it's served: its status, headers and body
+    /// fields. Relative `body_file` and `body` file paths are resolved against
+    /// the directory of the mock config file.
+    pub fn prepare(&mut self, base_dir: &Path) -> Result<(), String> {
+        if !(100..=599).contains(&self.status) || StatusCode::from_u16(self.status).is_err() {
+            return Err(format!("invalid status code {}", self.status));
+        }
+        for (name, value) in &self.headers {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(format!("invalid header name {:?}", name));
+            }
+            if HeaderValue::from_str(value).is_err() {
+                return Err(format!("invalid value {:?} for header {}", value, name));
+            }
+        }
+        let bodies = [
+            !self.body.is_empty(),
+            self.body_file.is_some(),
+            self.body_base64.is_some(),
+            self.body_json.is_some(),
+        ];
+        if bodies.iter().filter(|set| **set).count() > 1 {
+            return Err(
+                "only one of body, body_file, body_base64 and body_json may be set".to_string(),
+            );
+        }
+        if self.times.is_some() && self.at_least.is_some() {
+            return Err("only one of times and at_least may be set".to_string());
+        }
+        if let Some(ref file) = self.body_file {
+            let resolved = base_dir.join(file);
+            if !resolved.is_file() {
+                return Err(format!("body_file {} does not exist", resolved.display()));
+            }
+            self.body_file = Some(resolved.to_string_lossy().into_owned());
+        }
+        if let Some(ref encoded) = self.body_base64 {
+            STANDARD
+                .decode(encoded.trim())
+                .map_err(|e| format!("body_base64 is not valid base64: {}", e))?;
+        }
+        if is_body_file(&self.body) {
+            let resolved = base_dir.join(&self.body);
+            if resolved.is_file() {
+                self.body = resolved.to_string_lossy().into_owned();
+            } else if Path::new(&self.body).is_file() {
+                // Older configs named body files relative to the working directory.
+                warn!(
+                    "body file {} is resolved against the working directory; make it relative to {} instead",
+                    self.body,
+                    base_dir.display()
+                );
+            } else {
+                // A `body` naming a missing file would be served as the literal file name.
+                return Err(format!(
+                    "body file {} does not exist (the file name would be served as the body)",
+                    resolved.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the body served for this mock.
+    pub fn load_body(&self) -> Result<Bytes, String> {
+        if let Some(ref file) = self.body_file {
+            return fs::read(file)
+                .map(Bytes::from)
+                .map_err(|e| format!("Error reading {}: {}", file, e));
+        }
+        if let Some(ref encoded) = self.body_base64 {
+            return STANDARD
+                .decode(encoded.trim())
+                .map(Bytes::from)
+                .map_err(|e| format!("Error decoding body_base64: {}", e));
+        }
+        if let Some(ref json) = self.body_json {
+            return Ok(Bytes::from(json.to_string()));
+        }
+        Ok(Bytes::from(load_body_content(&self.body)))
+    }
+
+    /// Describes the expectation the mock's hits don't meet, if any.
+    pub fn unmet_expectation(&self) -> Option<String> {
+        let hits = self.hits.get();
+        match (self.times, self.at_least) {
+            (Some(times), _) if hits != times => Some(format!("expected {} hit(s)", times)),
+            (_, Some(at_least)) if hits < at_least => {
+                Some(format!("expected at least {} hit(s)", at_least))
+            }
+            _ => None,
+        }
+    }
+
+    /// The file the body comes from, used to guess its content type.
+    pub fn body_file_name(&self) -> Option<&str> {
+        if self.body_json.is_some() {
+            return Some("body.json");
+        }
+        self.body_file
+            .as_deref()
+            .or(Some(self.body.as_str()).filter(|b| Path::new(b).is_file()))
+    }
+}
+
+/// The mocks the proxy currently serves. Mocks added through the admin API
+/// come first, newest first, then mocks from the config file, then mocks from
+/// the mock directory.
+#[derive(Debug, Default)]
+pub struct MockStore {
+    mocks: RwLock<Vec<Mock>>,
+    next_id: AtomicU64,
+    /// Forwarded requests no mock answered, counted by method and path.
+    unmatched: Mutex<BTreeMap<(String, String), u64>>,
+}
+
+/// Distinct unmatched requests remembered; later ones are not tracked.
+const MAX_UNMATCHED: usize = 1000;
+
+/// A mock whose expectation isn't met, as reported by `GET /verify`.
+#[derive(Debug, Serialize)]
+pub struct UnmetExpectation {
+    pub id: u64,
+    pub method: String,
+    pub path: String,
+    pub hits: u64,
+    pub message: String,
+}
+
+/// A request no mock answered, with how often it was seen.
+#[derive(Debug, Serialize)]
+pub struct Unmatched {
+    pub method: String,
+    pub path: String,
+    pub count: u64,
+}
+
+/// The result of checking every mock's expectation.
+#[derive(Debug, Serialize)]
+pub struct Verification {
+    pub ok: bool,
+    pub unmet: Vec<UnmetExpectation>,
+    pub unmatched: Vec<Unmatched>,
+}
+
+impl MockStore {
+    pub fn new(mocks: Vec<Mock>) -> MockStore {
+        let store = MockStore::default();
+        store.replace_source(MockSource::Config, mocks);
+        store
+    }
+
+    fn assign_id(&self, mock: &mut Mock) {
+        mock.id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+    }
+
+    /// Every mock, in the order they are matched.
+    pub fn list(&self) -> Vec<Mock> {
+        self.mocks.read().unwrap().clone()
+    }
+
+    /// Adds a mock ahead of all others, returning its id.
+    pub fn add(&self, mut mock: Mock) -> u64 {
+        self.assign_id(&mut mock);
+        mock.source = MockSource::Admin;
+        let id = mock.id;
+        self.mocks.write().unwrap().insert(0, mock);
+        id
+    }
+
+    /// Replaces the mock with this id, keeping its place and source. Its hit
+    /// count starts over.
+    /// Returns `false` if there is no such mock.
+    pub fn update(&self, id: u64, mut mock: Mock) -> bool {
+        let mut mocks = self.mocks.write().unwrap();
+        match mocks.iter_mut().find(|m| m.id == id) {
+            Some(current) => {
+                mock.id = id;
+                mock.source = current.source.clone();
+                *current = mock;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the mock with this id. Returns `false` if there is no such mock.
+    pub fn remove(&self, id: u64) -> bool {
+        let mut mocks = self.mocks.write().unwrap();
+        let before = mocks.len();
+        mocks.retain(|m| m.id != id);
+        mocks.len() != before
+    }
+
+    pub fn len(&self) -> usize {
+        self.mocks.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mocks.read().unwrap().is_empty()
+    }
+
+    /// Returns the first mock answering this method and path, counting the
+    /// hit.
+    pub fn find(&self, method: &str, path: &str) -> Option<Mock> {
+        let mocks = self.mocks.read().unwrap();
+        let mock = mocks.iter().find(|m| m.matches(method, path))?;
+        mock.hits.count();
+        Some(mock.clone())
+    }
+
+    /// Remembers a request no mock answered and that went upstream.
+    pub fn note_unmatched(&self, method: &str, path: &str) {
+        let mut unmatched = self.unmatched.lock().unwrap();
+        let key = (method.to_string(), path.to_string());
+        if let Some(count) = unmatched.get_mut(&key) {
+            *count += 1;
+        } else if unmatched.len() < MAX_UNMATCHED {
+            unmatched.insert(key, 1);
+        }
+    }
+
+    /// The mocks that came closest to answering a request none answered.
+    pub fn near_misses(&self, method: &str, path: &str) -> Vec<NearMiss> {
+        near_miss::rank(&self.mocks.read().unwrap(), method, path)
+    }
+
+    /// Checks every mock's expectation. Unmatched requests don't fail the
+    /// check on their own, but are listed to help find out why one did.
+    pub fn verify(&self) -> Verification {
+        let unmet: Vec<UnmetExpectation> = self
+            .mocks
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|m| {
+                m.unmet_expectation().map(|message| UnmetExpectation {
+                    id: m.id,
+                    method: m.method.clone(),
+                    path: m.path.clone(),
+                    hits: m.hits.get(),
+                    message,
+                })
+            })
+            .collect();
+        let unmatched = self
+            .unmatched
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((method, path), count)| Unmatched {
+                method: method.clone(),
+                path: path.clone(),
+                count: *count,
+            })
+            .collect();
+        Verification {
+            ok: unmet.is_empty(),
+            unmet,
+            unmatched,
+        }
+    }
+
+    /// Sets every hit count back to zero and forgets the unmatched requests,
+    /// e.g. between two tests.
+    pub fn reset_hits(&self) {
+        for mock in self.mocks.read().unwrap().iter() {
+            mock.hits.reset();
+        }
+        self.unmatched.lock().unwrap().clear();
+    }
+
+    /// Swaps every mock from `source` for a fresh set. A mock whose method and
+    /// path were already served keeps its id and hit count, so a reload
+    /// doesn't reset expectations or the ids used by the admin API.
+    pub fn replace_source(&self, source: MockSource, mut mocks: Vec<Mock>) {
+        let mut current = self.mocks.write().unwrap();
+        let mut previous: Vec<Mock> = Vec::new();
+        current.retain(|m| {
+            if m.source == source {
+                previous.push(m.clone());
+                false
+            } else {
+                true
+            }
+        });
+        for mock in mocks.iter_mut() {
+            mock.source = source.clone();
+            let kept = previous
+                .iter()
+                .position(|p| p.method.eq_ignore_ascii_case(&mock.method) && p.path == mock.path);
+            match kept {
+                Some(i) => {
+                    let kept = previous.remove(i);
+                    mock.id = kept.id;
+                    mock.hits = kept.hits;
+                }
+                None => self.assign_id(mock),
+            }
+        }
+        current.extend(mocks);
+    }
+}
+
+/// Loads the body content from a file only if the `body_value` ends with .json, .txt, or .html.
+/// Otherwise returns the literal `body_value`.
+fn load_body_content(body_value: &str) -> String {
+    // Convert &str to `Path` so we can check the extension.
+    let path = Path::new(body_value);
+    let extension = path.extension().and_then(|ext| ext.to_str());
+
+    // Check for recognized extensions
+    match extension {
+        Some("json") | Some("txt") | Some("html") => {
+            // Attempt to read the file. If it fails, log and return the original string.
+            match fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    error!("Error reading {}: {}", body_value, e);
+                    body_value.to_string()
+                }
+            }
+        }
+        // If extension not recognized or missing, return just the literal
+        _ => body_value.to_string(),
+    }
+}
+
+/// Whether a `body` value names a file `load_body_content` would read.
+fn is_body_file(body_value: &str) -> bool {
+    matches!(
+        Path::new(body_value)
+            .extension()
+            .and_then(|ext| ext.to_str()),
+        Some("json") | Some("txt") | Some("html")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn mock(method: &str, path: &str) -> Mock {
+        serde_json::from_value(serde_json::json!({ "method": method, "path": path })).unwrap()
+    }
+
+    #[test]
+    fn matches_path_parameters() {
+        let mock = mock("GET", "/v1/users/{id}/orders");
+        assert!(mock.matches("get", "/v1/users/42/orders"));
+        assert!(mock.matches("GET", "/V1/Users/abc/Orders"));
+        assert!(!mock.matches("GET", "/v1/users//orders"));
+        assert!(!mock.matches("GET", "/v1/users/42"));
+        assert!(!mock.matches("GET", "/v1/users/42/orders/7"));
+        assert!(!mock.matches("POST", "/v1/users/42/orders"));
+    }
+
+    #[test]
+    fn matches_literal_paths_ignoring_case() {
+        let mock = mock("GET", "/v1/Health");
+        assert!(mock.matches("GET", "/v1/health"));
+        assert!(!mock.matches("GET", "/v1/health/"));
+    }
+
+    /// Prepares a `GET /` mock with the given body fields and loads its body.
+    fn load(dir: &Path, fields: serde_json::Value) -> Result<Bytes, String> {
+        let mut value = serde_json::json!({ "method": "GET", "path": "/" });
+        if let (Some(value), serde_json::Value::Object(fields)) = (value.as_object_mut(), fields) {
+            value.extend(fields);
+        }
+        let mut mock: Mock = serde_json::from_value(value).unwrap();
+        mock.prepare(dir)?;
+        mock.load_body()
+    }
+
+    #[test]
+    fn loads_each_body_source() {
+        let dir = TempDir::new("mock-body");
+        dir.write("fixtures/logo.png", "\u{89}PNG");
+        dir.write("data.json", "{\"ok\":true}");
+
+        let body = load(
+            &dir,
+            serde_json::json!({ "body_file": "fixtures/logo.png" }),
+        );
+        assert_eq!(body.unwrap(), "\u{89}PNG".as_bytes());
+        let body = load(&dir, serde_json::json!({ "body_base64": "aGk=\n" }));
+        assert_eq!(body.unwrap(), "hi");
+        let body = load(&dir, serde_json::json!({ "body_json": { "a": [1] } }));
+        assert_eq!(body.unwrap(), r#"{"a":[1]}"#);
+        // Legacy `body` files are resolved against the config directory too.
+        let body = load(&dir, serde_json::json!({ "body": "data.json" }));
+        assert_eq!(body.unwrap(), r#"{"ok":true}"#);
+        let body = load(&dir, serde_json::json!({ "body": "plain" }));
+        assert_eq!(body.unwrap(), "plain");
+    }
+
+    #[test]
+    fn rejects_bad_body_sources() {
+        let dir = TempDir::new("mock-body");
+        dir.write("data.json", "{}");
+        let err = |fields| load(&dir, fields).unwrap_err();
+
+        let e = err(serde_json::json!({ "body": "x", "body_json": {} }));
+        assert_eq!(
+            e,
+            "only one of body, body_file, body_base64 and body_json may be set"
+        );
+        let e = err(serde_json::json!({ "body_file": "missing.bin" }));
+        assert!(
+            e.starts_with("body_file ") && e.ends_with("missing.bin does not exist"),
+            "{}",
+            e
+        );
+        let e = err(serde_json::json!({ "body_base64": "not base64!" }));
+        assert!(e.starts_with("body_base64 is not valid base64"), "{}", e);
+        let e = err(serde_json::json!({ "body": "missing.json" }));
+        assert!(e.contains("missing.json does not exist"), "{}", e);
+    }
+
+    #[test]
+    fn counts_hits_through_reloads() {
+        let store = MockStore::new(vec![
+            mock("GET", "/v1/users/{id}"),
+            mock("GET", "/v1/health"),
+        ]);
+        assert_eq!(store.find("GET", "/v1/users/1").unwrap().hits.get(), 1);
+        assert_eq!(store.find("GET", "/v1/users/2").unwrap().hits.get(), 2);
+        assert!(store.find("POST", "/v1/users/2").is_none());
+
+        // A reload keeps the count of a mock it keeps.
+        store.replace_source(MockSource::Config, vec![mock("GET", "/v1/users/{id}")]);
+        let hits: Vec<u64> = store.list().iter().map(|m| m.hits.get()).collect();
+        assert_eq!(hits, [2]);
+
+        store.reset_hits();
+        assert_eq!(store.list()[0].hits.get(), 0);
+    }
+}
@@ -0,0 +1,340 @@
+use crate::body_rules::BodyRule;
+use crate::cache::CacheRule;
+use crate::cors::CorsRule;
+use crate::header_rules::HeaderRule;
+use crate::mock::Mock;
+use crate::split::SplitRule;
+use crate::startup::{Category, Problems};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The top-level structure of a mock config file:
+/// e.g.
+/// include = ["auth.toml", "users/*.toml"]
+///
+/// [[mocks]]
+/// method = "GET"
+/// ...
+///
+/// [[cache]]
+/// path = "/v1/slow/*"
+/// ttl = 300
+///
+/// [[splits]]
+/// path = "/v1/*"
+/// ...
+///
+/// [[request_headers]]
+/// action = "set"
+/// ...
+///
+/// [[request_body]]
+/// action = "form_set"
+/// ...
+///
+/// [[cors]]
+/// origins = ["http://localhost:3000"]
+/// ...
+///
+/// The same structure can be written in YAML (`.yaml`/`.yml`) or JSON (`.json`).
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct MockFile {
+    /// Other mock config files (glob patterns, relative to this file) loaded
+    /// underneath this one.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub mocks: Vec<Mock>,
+    #[serde(default)]
+    pub cache: Vec<CacheRule>,
+    #[serde(default)]
+    pub splits: Vec<SplitRule>,
+    #[serde(default)]
+    pub request_headers: Vec<HeaderRule>,
+    #[serde(default)]
+    pub response_headers: Vec<HeaderRule>,
+    #[serde(default)]
+    pub request_body: Vec<BodyRule>,
+    #[serde(default)]
+    pub response_body: Vec<BodyRule>,
+    #[serde(default)]
+    pub cors: Vec<CorsRule>,
+}
+
+impl MockFile {
+    /// Layers `higher` on top of this file. Its mocks, cache, split and CORS
+    /// rules are checked first (the first match wins), and its rewrite rules
+    /// run last (so their changes stick).
+    fn merge(&mut self, higher: MockFile) {
+        fn first<T>(lower: &mut Vec<T>, mut higher: Vec<T>) {
+            higher.append(lower);
+            *lower = higher;
+        }
+        first(&mut self.mocks, higher.mocks);
+        first(&mut self.cache, higher.cache);
+        first(&mut self.splits, higher.splits);
+        first(&mut self.cors, higher.cors);
+        self.request_headers.extend(higher.request_headers);
+        self.response_headers.extend(higher.response_headers);
+        self.request_body.extend(higher.request_body);
+        self.response_body.extend(higher.response_body);
+    }
+}
+
+/// Loads every `--mock-config` file into one set of mocks and rules, on top
+/// of the mocks inlined in the proxy config file (given as its path and
+/// already interpolated value).
+///
+/// Later files take precedence over earlier ones, and a file takes precedence
+/// over the files it includes. Files that cannot be read or parsed, and mocks
+/// with a broken body, are skipped and added to `problems`.
+pub fn load(
+    config_file: Option<(&Path, &Value)>,
+    paths: &[String],
+    problems: &mut Problems,
+) -> MockFile {
+    let mut merged = MockFile::default();
+    if let Some((path, value)) = config_file {
+        let parsed = serde_json::from_value(value.clone()).map_err(|e| e.to_string());
+        if let Some(file) = load_parsed(path, parsed, &mut Vec::new(), problems) {
+            merged.merge(file);
+        }
+    }
+    for path in paths {
+        let mut stack = Vec::new();
+        if let Some(file) = load_file(Path::new(path), &mut stack, problems) {
+            merged.merge(file);
+        }
+    }
+    merged
+}
+
+/// Loads one file and, underneath it, everything it includes. `stack` holds
+/// the files being loaded, to catch include cycles.
+fn load_file(path: &Path, stack: &mut Vec<PathBuf>, problems: &mut Problems) -> Option<MockFile> {
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if stack.contains(&canonical) {
+        problems.push(
+            Category::Mocks,
+            format!("Include cycle at mock config {}", path.display()),
+        );
+        return None;
+    }
+
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            problems.push(
+                Category::Mocks,
+                format!(
+                    "Failed to read mock config file {}: {}",
+                    path.display(),
+                    err
+                ),
+            );
+            return None;
+        }
+    };
+    load_parsed(path, parse(path, &contents), stack, problems)
+}
+
+fn load_parsed(
+    path: &Path,
+    parsed: Result<MockFile, String>,
+    stack: &mut Vec<PathBuf>,
+    problems: &mut Problems,
+) -> Option<MockFile> {
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut parsed = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            problems.push(
+                Category::Mocks,
+                format!("Failed to parse mock config ({}): {}", path.display(), err),
+            );
+            return None;
+        }
+    };
+
+    // A broken mock body is a startup problem, not a silent fallback.
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    parsed
+        .mocks
+        .retain_mut(|mock| match mock.prepare(base_dir) {
+            Ok(()) => true,
+            Err(err) => {
+                problems.push(
+                    Category::Mocks,
+                    format!(
+                        "Invalid mock {} {} in {}: {}",
+                        mock.method,
+                        mock.path,
+                        path.display(),
+                        err
+                    ),
+                );
+                false
+            }
+        });
+    info!(
+        "Loaded {} mock(s) from {}",
+        parsed.mocks.len(),
+        path.display()
+    );
+
+    stack.push(canonical);
+    let mut merged = MockFile::default();
+    for pattern in std::mem::take(&mut parsed.include) {
+        let pattern = base_dir.join(&pattern).to_string_lossy().into_owned();
+        let mut matches: Vec<PathBuf> = match glob::glob(&pattern) {
+            Ok(paths) => paths.flatten().collect(),
+            Err(err) => {
+                problems.push(
+                    Category::Mocks,
+                    format!("Invalid include pattern {}: {}", pattern, err),
+                );
+                continue;
+            }
+        };
+        if matches.is_empty() {
+            warn!("Include {} in {} matched no files", pattern, path.display());
+        }
+        matches.sort();
+        for included in matches {
+            if let Some(file) = load_file(&included, stack, problems) {
+                merged.merge(file);
+            }
+        }
+    }
+    stack.pop();
+
+    merged.merge(parsed);
+    Some(merged)
+}
+
+/// Parses a config file in the format given by its extension (TOML by default).
+pub fn parse<T: DeserializeOwned>(path: &Path, contents: &str) -> Result<T, String> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+        Some("json") => serde_json::from_str(contents).map_err(|e| e.to_string()),
+        _ => toml::from_str(contents).map_err(|e| e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn load_paths(paths: &[PathBuf]) -> (MockFile, Vec<String>) {
+        let paths: Vec<String> = paths
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+        let mut problems = Problems::default();
+        let file = load(None, &paths, &mut problems);
+        (file, problems.messages().map(str::to_string).collect())
+    }
+
+    fn bodies(file: &MockFile) -> Vec<&str> {
+        file.mocks.iter().map(|m| m.body.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_toml_yaml_and_json() {
+        let dir = TempDir::new("mock-config");
+        let toml = dir.write(
+            "a.toml",
+            "[[mocks]]\nmethod = \"GET\"\npath = \"/a\"\nbody = \"toml\"\n",
+        );
+        let yaml = dir.write(
+            "b.yml",
+            "mocks:\n  - method: GET\n    path: /b\n    body: yaml\n",
+        );
+        let json = dir.write(
+            "c.json",
+            r#"{"mocks": [{"method": "GET", "path": "/c", "body": "json"}]}"#,
+        );
+        let (file, problems) = load_paths(&[toml, yaml, json]);
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(bodies(&file), ["json", "yaml", "toml"]);
+    }
+
+    #[test]
+    fn loads_includes_underneath_the_including_file() {
+        let dir = TempDir::new("mock-config");
+        let main = dir.write(
+            "main.toml",
+            "include = [\"parts/*.toml\"]\n\
+             [[mocks]]\nmethod = \"GET\"\npath = \"/x\"\nbody = \"main\"\n\
+             [[request_headers]]\naction = \"remove\"\nname = \"X-Main\"\n",
+        );
+        for part in ["b", "a"] {
+            dir.write(
+                &format!("parts/{}.toml", part),
+                format!(
+                    "[[mocks]]\nmethod = \"GET\"\npath = \"/x\"\nbody = \"{0}\"\n\
+                     [[request_headers]]\naction = \"remove\"\nname = \"X-{0}\"\n",
+                    part
+                ),
+            );
+        }
+        dir.write("parts/ignored.yaml", "not: [a, mock, file");
+        let (file, problems) = load_paths(&[main]);
+        assert!(problems.is_empty(), "{:?}", problems);
+        // The including file's mocks are checked first, and its rewrite rules run last.
+        assert_eq!(bodies(&file), ["main", "b", "a"]);
+        let names: Vec<String> = file
+            .request_headers
+            .iter()
+            .map(|rule| match &rule.action {
+                crate::header_rules::HeaderAction::Remove { name } => name.to_string(),
+                other => panic!("unexpected rule {:?}", other),
+            })
+            .collect();
+        assert_eq!(names, ["x-a", "x-b", "x-main"]);
+    }
+
+    #[test]
+    fn later_files_take_precedence() {
+        let dir = TempDir::new("mock-config");
+        let files: Vec<PathBuf> = ["first", "second"]
+            .iter()
+            .map(|name| {
+                dir.write(
+                    &format!("{}.toml", name),
+                    format!(
+                        "[[mocks]]\nmethod = \"GET\"\npath = \"/x\"\nbody = \"{}\"\n",
+                        name
+                    ),
+                )
+            })
+            .collect();
+        let (file, _) = load_paths(&files);
+        assert_eq!(bodies(&file), ["second", "first"]);
+    }
+
+    #[test]
+    fn reports_include_cycles_and_broken_files() {
+        let dir = TempDir::new("mock-config");
+        let a = dir.write(
+            "a.toml",
+            "include = [\"b.toml\"]\n[[mocks]]\nmethod = \"GET\"\npath = \"/a\"\nbody = \"a\"\n",
+        );
+        dir.write(
+            "b.toml",
+            "include = [\"a.toml\"]\n[[mocks]]\nmethod = \"GET\"\npath = \"/b\"\nbody = \"b\"\n",
+        );
+        let broken = dir.write("broken.toml", "[[mocks]\n");
+        let (file, problems) = load_paths(&[a, broken]);
+        assert_eq!(bodies(&file), ["a", "b"]);
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("Include cycle at mock config "));
+        assert!(problems[0].ends_with("a.toml"), "{}", problems[0]);
+        assert!(problems[1].starts_with("Failed to parse mock config ("));
+    }
+}
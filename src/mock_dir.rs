@@ -0,0 +1,297 @@
+use crate::mock::{Hits, Mock, MockSource, MockStore};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+const METHODS: &[&str] = &["GET", "POST", "PUT", "PATCH", "DELETE", "HEAD", "OPTIONS"];
+
+/// Suffix of the optional sidecar file holding a mock's headers, e.g.
+/// `users.POST.201.json.headers.toml` next to `users.POST.201.json`.
+const HEADERS_SUFFIX: &str = ".headers.toml";
+
+/// Builds mocks from a directory tree (`--mock-dir`), where every file is a
+/// mock body and its location names the route:
+///
+/// mocks/GET/v1/users/__id__.json   -> GET /v1/users/{id}
+/// mocks/v1/users.POST.201.json     -> POST /v1/users, status 201
+/// mocks/v1/health.txt              -> GET /v1/health
+/// mocks/v1/orders/index.json       -> GET /v1/orders
+///
+/// The method comes from a leading method directory or a method token in the
+/// file name (GET if neither), and the status from a three-digit token (200
+/// if missing). Any other dotted part stays in the path, so `app.min.js`
+/// answers `/app.min`. Headers go into an optional `<file>.headers.toml`
+/// sidecar.
+pub fn load(dir: &Path) -> Vec<Mock> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files);
+    files.sort();
+
+    let mut mocks = Vec::new();
+    for file in files {
+        let name = file.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if name.starts_with('.') || name.ends_with(HEADERS_SUFFIX) {
+            continue;
+        }
+        match mock_for_file(dir, &file) {
+            Ok(mock) => mocks.push(mock),
+            Err(e) => warn!("Skipping mock file {}: {}", file.display(), e),
+        }
+    }
+    mocks
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to read mock directory {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+fn mock_for_file(root: &Path, file: &Path) -> Result<Mock, String> {
+    let relative = file
+        .strip_prefix(root)
+        .map_err(|e| e.to_string())?
+        .to_string_lossy()
+        .replace('\\', "/");
+    let mut segments: Vec<String> = relative.split('/').map(str::to_string).collect();
+    let file_name = segments.pop().unwrap_or_default();
+
+    let mut method = None;
+    if segments
+        .first()
+        .is_some_and(|s| METHODS.contains(&s.as_str()))
+    {
+        method = Some(segments.remove(0));
+    }
+
+    // Split "users.POST.201.json" into the stem and its tokens. Other dotted
+    // parts, as in "app.min.js", stay in the stem.
+    let mut parts: Vec<&str> = file_name.split('.').collect();
+    if parts.len() > 1 {
+        parts.pop();
+    }
+    let mut stem = vec![parts.remove(0)];
+    let mut status = 200;
+    for token in parts {
+        if METHODS.contains(&token) {
+            method = Some(token.to_string());
+        } else if token.len() == 3 && token.parse::<u16>().is_ok() {
+            status = token.parse().unwrap_or(200);
+        } else {
+            stem.push(token);
+        }
+    }
+    let stem = stem.join(".");
+    if stem != "index" {
+        segments.push(stem.to_string());
+    }
+
+    let path = format!(
+        "/{}",
+        segments
+            .iter()
+            .map(
+                |s| match s.strip_prefix("__").and_then(|s| s.strip_suffix("__")) {
+                    Some(param) if !param.is_empty() => format!("{{{}}}", param),
+                    _ => s.clone(),
+                }
+            )
+            .collect::<Vec<_>>()
+            .join("/")
+    );
+
+    let sidecar = PathBuf::from(format!("{}{}", file.display(), HEADERS_SUFFIX));
+    let headers = if sidecar.is_file() {
+        let contents = fs::read_to_string(&sidecar).map_err(|e| e.to_string())?;
+        toml::from_str::<HashMap<String, String>>(&contents)
+            .map_err(|e| format!("invalid {}: {}", sidecar.display(), e))?
+    } else {
+        HashMap::new()
+    };
+
+    let mut mock = Mock {
+        id: 0,
+        method: method.unwrap_or_else(|| "GET".to_string()),
+        path,
+        status,
+        body: String::new(),
+        body_file: Some(file.to_string_lossy().into_owned()),
+        body_base64: None,
+        body_json: None,
+        headers,
+        times: None,
+        at_least: None,
+        hits: Hits::default(),
+        source: MockSource::Directory,
+    };
+    // `body_file` already includes the directory.
+    mock.prepare(Path::new(""))?;
+    Ok(mock)
+}
+
+/// A cheap fingerprint of the tree: every file with its size and mtime.
+fn fingerprint(dir: &Path) -> Vec<(PathBuf, u64, Option<SystemTime>)> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files);
+    let mut fingerprint: Vec<_> = files
+        .into_iter()
+        .map(|f| {
+            let meta = fs::metadata(&f).ok();
+            let len = meta.as_ref().map_or(0, |m| m.len());
+            let modified = meta.and_then(|m| m.modified().ok());
+            (f, len, modified)
+        })
+        .collect();
+    fingerprint.sort();
+    fingerprint
+}
+
+/// Polls the directory and reloads its mocks whenever a file is added,
+/// removed or changed.
+pub fn watch(dir: PathBuf, store: Arc<MockStore>) {
+    tokio::spawn(async move {
+        let mut last = scan(&dir, fingerprint).await;
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let current = scan(&dir, fingerprint).await;
+            if current != last {
+                let mocks = scan(&dir, load).await;
+                info!(
+                    "Reloaded {} mock(s) from {} after a change",
+                    mocks.len(),
+                    dir.display()
+                );
+                store.replace_source(MockSource::Directory, mocks);
+                last = current;
+            }
+        }
+    });
+}
+
+/// Walks the tree on the blocking pool, so the file system calls don't hold
+/// up the runtime's workers.
+async fn scan<T: Default + Send + 'static>(dir: &Path, walk: fn(&Path) -> T) -> T {
+    let dir = dir.to_path_buf();
+    match tokio::task::spawn_blocking(move || walk(&dir)).await {
+        Ok(result) => result,
+        Err(e) => {
+            error!("Failed to scan mock directory: {}", e);
+            T::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    /// A mock directory holding the given files.
+    fn tree(files: &[(&str, &str)]) -> TempDir {
+        let root = TempDir::new("mock-dir");
+        for (path, contents) in files {
+            root.write(path, contents);
+        }
+        root
+    }
+
+    fn route(mock: &Mock) -> (&str, &str, u16) {
+        (mock.method.as_str(), mock.path.as_str(), mock.status)
+    }
+
+    #[test]
+    fn reads_method_directories_and_path_parameters() {
+        let root = tree(&[("GET/v1/users/__id__.json", "{}")]);
+        let mock = mock_for_file(&root, &root.join("GET/v1/users/__id__.json")).unwrap();
+        assert_eq!(route(&mock), ("GET", "/v1/users/{id}", 200));
+        assert!(mock.matches("GET", "/v1/users/42"));
+        assert_eq!(mock.source, MockSource::Directory);
+    }
+
+    #[test]
+    fn reads_method_and_status_tokens() {
+        let root = tree(&[("v1/users.POST.201.json", "{}")]);
+        let mock = mock_for_file(&root, &root.join("v1/users.POST.201.json")).unwrap();
+        assert_eq!(route(&mock), ("POST", "/v1/users", 201));
+    }
+
+    #[test]
+    fn defaults_to_get_and_200() {
+        let root = tree(&[("v1/health.txt", "ok"), ("v1/orders/index.json", "[]")]);
+        let health = mock_for_file(&root, &root.join("v1/health.txt")).unwrap();
+        assert_eq!(route(&health), ("GET", "/v1/health", 200));
+        let orders = mock_for_file(&root, &root.join("v1/orders/index.json")).unwrap();
+        assert_eq!(route(&orders), ("GET", "/v1/orders", 200));
+    }
+
+    #[test]
+    fn merges_headers_from_the_sidecar() {
+        let root = tree(&[
+            ("v1/users.POST.201.json", "{}"),
+            ("v1/users.POST.201.json.headers.toml", "X-Trace = \"1\"\n"),
+        ]);
+        let mocks = load(&root);
+        assert_eq!(mocks.len(), 1, "the sidecar is not a mock of its own");
+        assert_eq!(route(&mocks[0]), ("POST", "/v1/users", 201));
+        assert_eq!(
+            mocks[0].headers.get("X-Trace").map(String::as_str),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn keeps_other_dotted_parts_in_the_path() {
+        let root = tree(&[
+            ("assets/app.min.js", "x"),
+            ("v1.2.json", "{}"),
+            ("v1/report.2024.POST.json", "{}"),
+        ]);
+        let route_of = |file: &str| {
+            let mock = mock_for_file(&root, &root.join(file)).unwrap();
+            (mock.method, mock.path, mock.status)
+        };
+        assert_eq!(
+            route_of("assets/app.min.js"),
+            ("GET".to_string(), "/assets/app.min".to_string(), 200)
+        );
+        assert_eq!(
+            route_of("v1.2.json"),
+            ("GET".to_string(), "/v1.2".to_string(), 200)
+        );
+        assert_eq!(
+            route_of("v1/report.2024.POST.json"),
+            ("POST".to_string(), "/v1/report.2024".to_string(), 200)
+        );
+    }
+
+    #[test]
+    fn skips_files_with_bad_sidecars() {
+        let root = tree(&[
+            ("v1/items.json", "[]"),
+            ("v1/items.json.headers.toml", "not toml"),
+            ("v1/ok.json", "{}"),
+        ]);
+        let err = mock_for_file(&root, &root.join("v1/items.json")).unwrap_err();
+        assert!(err.starts_with("invalid "), "{}", err);
+        let mocks = load(&root);
+        assert_eq!(
+            mocks.iter().map(route).collect::<Vec<_>>(),
+            [("GET", "/v1/ok", 200)]
+        );
+    }
+}
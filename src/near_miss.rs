@@ -0,0 +1,199 @@
+use crate::mock::Mock;
+use warp::http::HeaderValue;
+
+/// How many of the closest mocks are reported.
+const MAX_NEAR_MISSES: usize = 3;
+
+/// A mock that came close to answering a request, and why it didn't.
+#[derive(Debug, Clone)]
+pub struct NearMiss {
+    pub id: u64,
+    pub method: String,
+    pub path: String,
+    pub reasons: Vec<String>,
+    /// Lower is closer.
+    score: usize,
+}
+
+/// The mocks closest to matching this request, closest first. Mocks whose
+/// path mostly differs from the request's are left out.
+pub fn rank(mocks: &[Mock], method: &str, path: &str) -> Vec<NearMiss> {
+    let mut near_misses: Vec<NearMiss> = mocks
+        .iter()
+        .filter_map(|mock| compare(mock, method, path))
+        .collect();
+    // `sort_by_key` is stable, so ties keep the match order.
+    near_misses.sort_by_key(|n| n.score);
+    near_misses.truncate(MAX_NEAR_MISSES);
+    near_misses
+}
+
+/// Why `mock` doesn't answer the request, or `None` if it's not worth
+/// mentioning.
+fn compare(mock: &Mock, method: &str, path: &str) -> Option<NearMiss> {
+    let mut reasons = Vec::new();
+    let mut score = 0;
+
+    if !mock.method.eq_ignore_ascii_case(method) {
+        reasons.push(format!("method is {}, not {}", mock.method, method));
+        score += 2;
+    }
+
+    // Mocks are matched on the path alone, so a recorded `path = "/a?b=c"`
+    // never fires.
+    let (mock_path, mock_query) = match mock.path.split_once('?') {
+        Some((mock_path, query)) => (mock_path, Some(query)),
+        None => (mock.path.as_str(), None),
+    };
+    if let Some(query) = mock_query {
+        reasons.push(format!(
+            "its path has a query string ({}), but mocks only match the path",
+            query
+        ));
+        score += 1;
+    }
+
+    let template: Vec<&str> = mock_path.trim_matches('/').split('/').collect();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let mut common = 0;
+    let mut different = 0;
+    for (t, s) in template.iter().zip(&segments) {
+        let is_param = t.starts_with('{') && t.ends_with('}');
+        if (is_param && !s.is_empty()) || t.eq_ignore_ascii_case(s) {
+            common += 1;
+        } else {
+            reasons.push(format!("segment '{}' doesn't match '{}'", t, s));
+            different += 1;
+            score += 1;
+        }
+    }
+    // A shared `/v1` prefix alone doesn't make a mock close.
+    if common <= different {
+        return None;
+    }
+    if template.len() != segments.len() {
+        reasons.push(format!(
+            "path has {} segment(s), the request has {}",
+            template.len(),
+            segments.len()
+        ));
+        score += 2 * template.len().abs_diff(segments.len());
+    } else if mock_path.ends_with('/') != path.ends_with('/') && mock_path != "/" {
+        reasons.push(if mock_path.ends_with('/') {
+            "trailing slash: the mock's path ends with '/', the request's doesn't".to_string()
+        } else {
+            "trailing slash: the request's path ends with '/', the mock's doesn't".to_string()
+        });
+        score += 1;
+    }
+
+    if reasons.is_empty() {
+        return None;
+    }
+    Some(NearMiss {
+        id: mock.id,
+        method: mock.method.clone(),
+        path: mock.path.clone(),
+        reasons,
+        score,
+    })
+}
+
+impl NearMiss {
+    /// One line describing the mock and why it missed.
+    pub fn describe(&self) -> String {
+        format!(
+            "mock {} ({} {}): {}",
+            self.id,
+            self.method,
+            self.path,
+            self.reasons.join("; ")
+        )
+    }
+}
+
+/// The `X-Proxxyy-Near-Miss` debug header, if there is anything to report
+/// (and it fits in a header).
+pub fn header_value(near_misses: &[NearMiss]) -> Option<HeaderValue> {
+    if near_misses.is_empty() {
+        return None;
+    }
+    let value: Vec<String> = near_misses.iter().map(NearMiss::describe).collect();
+    HeaderValue::from_str(&value.join(" | ")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock(id: u64, method: &str, path: &str) -> Mock {
+        let mut mock: Mock =
+            serde_json::from_value(serde_json::json!({ "method": method, "path": path })).unwrap();
+        mock.id = id;
+        mock
+    }
+
+    fn reasons(mock_method: &str, mock_path: &str, method: &str, path: &str) -> Vec<String> {
+        compare(&mock(1, mock_method, mock_path), method, path)
+            .map(|n| n.reasons)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn explains_each_kind_of_miss() {
+        assert_eq!(
+            reasons("POST", "/v1/users", "GET", "/v1/users"),
+            ["method is POST, not GET"]
+        );
+        assert_eq!(
+            reasons("GET", "/v1/users?page=2", "GET", "/v1/users"),
+            ["its path has a query string (page=2), but mocks only match the path"]
+        );
+        assert_eq!(
+            reasons("GET", "/v1/users/me", "GET", "/v1/users/you"),
+            ["segment 'me' doesn't match 'you'"]
+        );
+        assert_eq!(
+            reasons("GET", "/v1/users/{id}/orders", "GET", "/v1/users/7"),
+            ["path has 4 segment(s), the request has 3"]
+        );
+        assert_eq!(
+            reasons("GET", "/v1/users/", "GET", "/v1/users"),
+            ["trailing slash: the mock's path ends with '/', the request's doesn't"]
+        );
+        assert_eq!(
+            reasons("GET", "/v1/users", "GET", "/v1/users/"),
+            ["trailing slash: the request's path ends with '/', the mock's doesn't"]
+        );
+    }
+
+    #[test]
+    fn leaves_out_matches_and_distant_mocks() {
+        assert!(reasons("GET", "/v1/users/{id}", "GET", "/v1/users/7").is_empty());
+        // Only the `/v1` prefix in common.
+        assert!(reasons("GET", "/v1/orders/recent", "GET", "/v1/users/7").is_empty());
+    }
+
+    #[test]
+    fn ranks_the_closest_mocks_first() {
+        let mocks = [
+            mock(1, "POST", "/v1/users/{id}/orders"),
+            mock(2, "POST", "/v1/users/{id}"),
+            mock(3, "GET", "/v1/users/{id}/"),
+            mock(4, "GET", "/v1/users/{id}?x=1"),
+            mock(5, "GET", "/v1/items"),
+        ];
+        let ranked = rank(&mocks, "GET", "/v1/users/7");
+        // Ties keep the match order; the top three are reported.
+        assert_eq!(ranked.iter().map(|n| n.id).collect::<Vec<_>>(), [3, 4, 2]);
+        assert_eq!(
+            ranked[0].describe(),
+            "mock 3 (GET /v1/users/{id}/): trailing slash: the mock's path ends with '/', the request's doesn't"
+        );
+        assert_eq!(
+            header_value(&ranked[2..]).unwrap(),
+            "mock 2 (POST /v1/users/{id}): method is POST, not GET"
+        );
+        assert!(header_value(&[]).is_none());
+    }
+}
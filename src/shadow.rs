@@ -0,0 +1,397 @@
+use crate::shutdown::InFlight;
+use bytes::Bytes;
+use log::{error, info, warn};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Arc;
+use warp::http::{HeaderMap, Method, StatusCode};
+
+/// Mirrors forwarded requests to a second ("shadow") upstream and reports
+/// where its responses differ from the primary target's.
+pub struct Shadow {
+    url: String,
+    compare_headers: Vec<String>,
+    ignore_paths: Vec<String>,
+    diff_file: Option<String>,
+    client: Client,
+    /// Mirrored requests count as in flight, so shutdown waits for them too.
+    in_flight: Arc<InFlight>,
+}
+
+/// The parts of the primary exchange the shadow response is compared against.
+pub struct Exchange {
+    pub method: Method,
+    pub path_and_query: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub status: StatusCode,
+    pub resp_headers: HeaderMap,
+    pub resp_body: Bytes,
+}
+
+impl Shadow {
+    pub fn new(
+        url: String,
+        compare_headers: Vec<String>,
+        ignore_paths: Vec<String>,
+        diff_file: Option<String>,
+        client: Client,
+        in_flight: Arc<InFlight>,
+    ) -> Shadow {
+        Shadow {
+            url: url.trim_end_matches('/').to_string(),
+            compare_headers,
+            ignore_paths,
+            diff_file,
+            client,
+            in_flight,
+        }
+    }
+
+    /// Sends a copy of the request to the shadow target in the background.
+    /// The client never waits for (or sees) the shadow response.
+    pub fn mirror(self: Arc<Self>, exchange: Exchange) {
+        let in_flight = self.in_flight.enter();
+        tokio::spawn(async move {
+            let _in_flight = in_flight;
+            let url = format!("{}{}", self.url, exchange.path_and_query);
+            let mut req_builder = self.client.request(exchange.method.clone(), &url);
+            for (name, value) in exchange.headers.iter() {
+                if name == warp::http::header::HOST {
+                    continue;
+                }
+                req_builder = req_builder.header(name, value);
+            }
+            if !exchange.body.is_empty() {
+                req_builder = req_builder.body(exchange.body.clone());
+            }
+
+            let response = match req_builder.send().await {
+                Ok(resp) => resp,
+                Err(err) => {
+                    warn!("Shadow request to {} failed: {}", url, err);
+                    return;
+                }
+            };
+            let status = response.status();
+            let headers = response.headers().clone();
+            let body = match response.bytes().await {
+                Ok(b) => b,
+                Err(err) => {
+                    warn!("Error reading shadow response body from {}: {}", url, err);
+                    return;
+                }
+            };
+
+            let differences = self.compare(&exchange, status, &headers, &body);
+            if differences.is_empty() {
+                info!(
+                    "Shadow response matches for {} {}",
+                    exchange.method, exchange.path_and_query
+                );
+            } else {
+                warn!(
+                    "Shadow response differs for {} {}:\n  {}",
+                    exchange.method,
+                    exchange.path_and_query,
+                    differences.join("\n  ")
+                );
+                self.record(&exchange, &differences);
+            }
+        });
+    }
+
+    /// Compares status, the selected headers and the body of both responses.
+    fn compare(
+        &self,
+        exchange: &Exchange,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Vec<String> {
+        let mut differences = Vec::new();
+
+        if exchange.status != status {
+            differences.push(format!(
+                "status: {} (primary) != {} (shadow)",
+                exchange.status.as_u16(),
+                status.as_u16()
+            ));
+        }
+
+        for name in &self.compare_headers {
+            let primary = header_value(&exchange.resp_headers, name);
+            let shadow = header_value(headers, name);
+            if primary != shadow {
+                differences.push(format!(
+                    "header {}: {:?} (primary) != {:?} (shadow)",
+                    name, primary, shadow
+                ));
+            }
+        }
+
+        // Encoded bodies can differ byte for byte while saying the same thing,
+        // so only identity bodies are compared.
+        let encodings = (
+            content_encoding(&exchange.resp_headers),
+            content_encoding(headers),
+        );
+        if encodings != (None, None) {
+            info!(
+                "Not comparing shadow response bodies for {} {}: encoded as {} (primary) and {} (shadow)",
+                exchange.method,
+                exchange.path_and_query,
+                encodings.0.as_deref().unwrap_or("identity"),
+                encodings.1.as_deref().unwrap_or("identity")
+            );
+            return differences;
+        }
+
+        match (
+            serde_json::from_slice::<Value>(&exchange.resp_body),
+            serde_json::from_slice::<Value>(body),
+        ) {
+            (Ok(primary), Ok(shadow)) => {
+                diff_json("", &primary, &shadow, &self.ignore_paths, &mut differences)
+            }
+            _ if exchange.resp_body.as_ref() != body => differences.push(format!(
+                "body: {} bytes (primary) != {} bytes (shadow)",
+                exchange.resp_body.len(),
+                body.len()
+            )),
+            _ => {}
+        }
+
+        differences
+    }
+
+    /// Appends the differences as one JSON line to the diff file, if configured.
+    fn record(&self, exchange: &Exchange, differences: &[String]) {
+        let Some(ref path) = self.diff_file else {
+            return;
+        };
+        let line = json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "method": exchange.method.as_str(),
+            "uri": exchange.path_and_query,
+            "differences": differences,
+        });
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(e) = result {
+            error!("Failed to record shadow differences to {}: {}", path, e);
+        }
+    }
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// The response's `Content-Encoding`, or `None` for identity.
+fn content_encoding(headers: &HeaderMap) -> Option<String> {
+    let encodings: Vec<String> = headers
+        .get_all(warp::http::header::CONTENT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|e| e.trim().to_lowercase())
+        .filter(|e| !e.is_empty() && e != "identity")
+        .collect();
+    Some(encodings.join(", ")).filter(|e| !e.is_empty())
+}
+
+/// Recursively compares two JSON values, skipping ignored JSON pointers.
+fn diff_json(
+    pointer: &str,
+    primary: &Value,
+    shadow: &Value,
+    ignore_paths: &[String],
+    differences: &mut Vec<String>,
+) {
+    if ignore_paths.iter().any(|p| pointer_matches(p, pointer)) {
+        return;
+    }
+    match (primary, shadow) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, value) in a {
+                let child = format!("{}/{}", pointer, escape_pointer(key));
+                match b.get(key) {
+                    Some(other) => diff_json(&child, value, other, ignore_paths, differences),
+                    None if !ignore_paths.iter().any(|p| pointer_matches(p, &child)) => {
+                        differences.push(format!("body {}: missing in shadow", child))
+                    }
+                    None => {}
+                }
+            }
+            for key in b.keys().filter(|k| !a.contains_key(*k)) {
+                let child = format!("{}/{}", pointer, escape_pointer(key));
+                if !ignore_paths.iter().any(|p| pointer_matches(p, &child)) {
+                    differences.push(format!("body {}: missing in primary", child));
+                }
+            }
+        }
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+            for (i, (x, y)) in a.iter().zip(b).enumerate() {
+                diff_json(
+                    &format!("{}/{}", pointer, i),
+                    x,
+                    y,
+                    ignore_paths,
+                    differences,
+                );
+            }
+        }
+        _ if primary != shadow => differences.push(format!(
+            "body {}: {} (primary) != {} (shadow)",
+            if pointer.is_empty() { "/" } else { pointer },
+            primary,
+            shadow
+        )),
+        _ => {}
+    }
+}
+
+/// Whether a JSON pointer lies at or below an ignore pattern.
+/// A `*` segment in the pattern matches any single key or index.
+fn pointer_matches(pattern: &str, pointer: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').skip(1).collect();
+    let pointer: Vec<&str> = pointer.split('/').skip(1).collect();
+    pattern.len() <= pointer.len()
+        && pattern
+            .iter()
+            .zip(&pointer)
+            .all(|(p, s)| *p == "*" || p == s)
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::headers;
+
+    fn shadow(ignore_paths: &[&str]) -> Shadow {
+        Shadow::new(
+            "http://shadow.local".to_string(),
+            vec!["Content-Type".to_string()],
+            ignore_paths.iter().map(|p| p.to_string()).collect(),
+            None,
+            Client::new(),
+            Arc::new(InFlight::default()),
+        )
+    }
+
+    fn exchange(resp_headers: HeaderMap, resp_body: &'static [u8]) -> Exchange {
+        Exchange {
+            method: Method::GET,
+            path_and_query: "/v1/users".to_string(),
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+            status: StatusCode::OK,
+            resp_headers,
+            resp_body: Bytes::from_static(resp_body),
+        }
+    }
+
+    fn diff(primary: Value, shadow: Value, ignore_paths: &[&str]) -> Vec<String> {
+        let ignore_paths: Vec<String> = ignore_paths.iter().map(|p| p.to_string()).collect();
+        let mut differences = Vec::new();
+        diff_json("", &primary, &shadow, &ignore_paths, &mut differences);
+        differences
+    }
+
+    #[test]
+    fn reports_json_differences_by_pointer() {
+        assert_eq!(
+            diff(
+                json!({ "id": 1, "tags": ["a", "b"], "a/b": true, "old": 1 }),
+                json!({ "id": 2, "tags": ["a", "c"], "a/b": false, "new": 1 }),
+                &[],
+            ),
+            [
+                "body /a~1b: true (primary) != false (shadow)",
+                "body /id: 1 (primary) != 2 (shadow)",
+                "body /old: missing in shadow",
+                "body /tags/1: \"b\" (primary) != \"c\" (shadow)",
+                "body /new: missing in primary",
+            ]
+        );
+        assert_eq!(
+            diff(json!([1, 2]), json!([1]), &[]),
+            ["body /: [1,2] (primary) != [1] (shadow)"]
+        );
+    }
+
+    #[test]
+    fn skips_ignored_pointers() {
+        let primary = json!({ "meta": { "at": 1 }, "items": [{ "id": 1, "n": "a" }], "x": 1 });
+        let shadow = json!({ "meta": { "at": 2 }, "items": [{ "id": 9, "n": "a" }] });
+        assert_eq!(
+            diff(
+                primary.clone(),
+                shadow.clone(),
+                &["/meta/at", "/items/*/id", "/x"]
+            ),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            diff(primary, shadow, &["/meta", "/items/0/id"]),
+            ["body /x: missing in shadow"]
+        );
+        assert!(pointer_matches("/items/*", "/items/3/id"));
+        assert!(!pointer_matches("/items/*/id", "/items/3"));
+        assert!(!pointer_matches("/item", "/items"));
+    }
+
+    #[test]
+    fn compares_status_headers_and_raw_bodies() {
+        let primary = exchange(headers(&[("content-type", "text/plain")]), b"hello");
+        let differences = shadow(&[]).compare(
+            &primary,
+            StatusCode::NOT_FOUND,
+            &headers(&[("content-type", "text/html")]),
+            b"hello!",
+        );
+        assert_eq!(
+            differences,
+            [
+                "status: 200 (primary) != 404 (shadow)",
+                "header Content-Type: Some(\"text/plain\") (primary) != Some(\"text/html\") (shadow)",
+                "body: 5 bytes (primary) != 6 bytes (shadow)",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_compares_identity_bodies() {
+        let gzip = headers(&[("content-encoding", "gzip")]);
+        let identity = headers(&[("content-encoding", "identity")]);
+        let shadow = shadow(&[]);
+
+        let primary = exchange(gzip.clone(), b"\x1f\x8b first stream");
+        assert!(shadow
+            .compare(&primary, StatusCode::OK, &gzip, b"\x1f\x8b second")
+            .is_empty());
+        assert!(shadow
+            .compare(&primary, StatusCode::OK, &HeaderMap::new(), b"{}")
+            .is_empty());
+
+        let primary = exchange(identity, br#"{"a": 1}"#);
+        assert_eq!(
+            shadow.compare(&primary, StatusCode::OK, &HeaderMap::new(), br#"{"a": 2}"#),
+            ["body /a: 1 (primary) != 2 (shadow)"]
+        );
+        assert_eq!(
+            content_encoding(&headers(&[("content-encoding", "GZIP, br")])).as_deref(),
+            Some("gzip, br")
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use log::{info, warn};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Resolves once the proxy is asked to stop, by Ctrl-C (SIGINT) or SIGTERM.
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl-C, shutting down"),
+        _ = terminate => info!("Received SIGTERM, shutting down"),
+    }
+}
+
+/// Counts the requests the proxy is still answering.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    count: AtomicUsize,
+}
+
+/// Held while a request is being answered; dropping it marks the request done.
+#[derive(Debug)]
+pub struct InFlightGuard {
+    in_flight: Arc<InFlight>,
+}
+
+impl InFlight {
+    pub fn enter(self: &Arc<Self>) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            in_flight: self.clone(),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// How waiting for in-flight requests ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Drained {
+    Finished,
+    TimedOut,
+    Interrupted,
+}
+
+/// Waits for a server that has stopped accepting connections to finish the
+/// requests it's answering, and for mirrored requests still running, for at
+/// most `timeout`. `interrupt` (a second Ctrl-C or SIGTERM) stops waiting
+/// right away.
+pub async fn drain(
+    server: impl Future<Output = ()>,
+    in_flight: &InFlight,
+    timeout: Duration,
+    interrupt: impl Future<Output = ()>,
+) -> Drained {
+    let pending = in_flight.count();
+    if pending > 0 {
+        info!(
+            "Waiting up to {}s for {} in-flight request(s) (press Ctrl-C again to stop now)",
+            timeout.as_secs(),
+            pending
+        );
+    }
+    let finished = async {
+        server.await;
+        // Mirrored requests outlive the replies they were copied from.
+        while in_flight.count() > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+    tokio::select! {
+        _ = finished => {
+            if pending > 0 {
+                info!("All in-flight requests finished");
+            }
+            Drained::Finished
+        }
+        _ = tokio::time::sleep(timeout) => {
+            warn!(
+                "{} request(s) still in flight after {}s, stopping anyway",
+                in_flight.count(),
+                timeout.as_secs()
+            );
+            Drained::TimedOut
+        }
+        _ = interrupt => {
+            warn!(
+                "Stopping without waiting for {} in-flight request(s)",
+                in_flight.count()
+            );
+            Drained::Interrupted
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::pending;
+
+    #[test]
+    fn counts_requests_until_their_guards_drop() {
+        let in_flight = Arc::new(InFlight::default());
+        let first = in_flight.enter();
+        let second = in_flight.enter();
+        assert_eq!(in_flight.count(), 2);
+        drop(first);
+        drop(second);
+        assert_eq!(in_flight.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn waits_for_requests_to_finish() {
+        let in_flight = Arc::new(InFlight::default());
+        let guard = in_flight.enter();
+        let server = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        // A mirrored request finishing after the server still holds shutdown up.
+        let mirror = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(guard);
+        });
+        let drained = drain(server, &in_flight, Duration::from_secs(5), pending()).await;
+        assert_eq!(drained, Drained::Finished);
+        assert!(mirror.is_finished());
+    }
+
+    #[tokio::test]
+    async fn stops_waiting_after_the_timeout() {
+        let in_flight = Arc::new(InFlight::default());
+        let _guard = in_flight.enter();
+        let drained = drain(pending(), &in_flight, Duration::from_millis(20), pending()).await;
+        assert_eq!(drained, Drained::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn stops_waiting_when_interrupted() {
+        let in_flight = Arc::new(InFlight::default());
+        let _guard = in_flight.enter();
+        let interrupt = async {};
+        let drained = drain(pending(), &in_flight, Duration::from_secs(60), interrupt).await;
+        assert_eq!(drained, Drained::Interrupted);
+    }
+}
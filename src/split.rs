@@ -0,0 +1,214 @@
+use rand::Rng;
+use serde::Deserialize;
+use warp::http::{HeaderMap, Method};
+
+/// Request header that forces a split route onto one of its targets, by name.
+pub const OVERRIDE_HEADER: &str = "x-proxxyy-target";
+
+/// Splits the traffic of a route between several upstreams
+/// (loaded from the mock config file).
+/// For example, from TOML:
+///
+/// [[splits]]
+/// path = "/v1/*"
+/// sticky_cookie = "session_id"
+///
+/// [[splits.targets]]
+/// name = "canary"
+/// url = "https://canary.example.com"
+/// weight = 10
+///
+/// [[splits.targets]]
+/// name = "stable"
+/// url = "https://api.example.com"
+/// weight = 90
+#[derive(Debug, Deserialize, Clone)]
+pub struct SplitRule {
+    #[serde(default)]
+    pub method: Option<String>,
+    pub path: String,
+    #[serde(default)]
+    pub sticky_cookie: Option<String>,
+    #[serde(default)]
+    pub sticky_header: Option<String>,
+    pub targets: Vec<SplitTarget>,
+}
+
+/// One of the upstreams of a split route.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SplitTarget {
+    pub name: String,
+    pub url: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// Picks the upstream for a request, or `None` if no split rule applies.
+///
+/// The override header wins; otherwise requests carrying the sticky cookie or
+/// header always land on the same target, and the rest are spread randomly
+/// according to the weights.
+pub fn choose_target<'a>(
+    rules: &'a [SplitRule],
+    method: &Method,
+    path: &str,
+    headers: &HeaderMap,
+) -> Option<&'a SplitTarget> {
+    let rule = rules.iter().find(|r| {
+        r.method
+            .as_ref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(method.as_str()))
+            && crate::path_matches(&r.path, path)
+    })?;
+
+    if let Some(forced) = headers.get(OVERRIDE_HEADER).and_then(|v| v.to_str().ok()) {
+        if let Some(target) = rule
+            .targets
+            .iter()
+            .find(|t| t.name.eq_ignore_ascii_case(forced.trim()))
+        {
+            return Some(target);
+        }
+    }
+
+    let total = total_weight(&rule.targets);
+    if total == 0 {
+        return rule.targets.first();
+    }
+
+    let bucket = match sticky_value(rule, headers) {
+        // A stable hash keeps sticky clients on their target across upgrades.
+        Some(value) => crate::cache::stable_hash(&value) % total,
+        None => rand::thread_rng().gen_range(0..total),
+    };
+    target_at(&rule.targets, bucket)
+}
+
+/// The sum of the weights, counted in `u64` so large weights can't overflow.
+fn total_weight(targets: &[SplitTarget]) -> u64 {
+    targets.iter().map(|t| t.weight as u64).sum()
+}
+
+/// Returns the target whose share of `0..total_weight` holds the bucket.
+fn target_at(targets: &[SplitTarget], bucket: u64) -> Option<&SplitTarget> {
+    let mut upper = 0;
+    targets.iter().find(|t| {
+        upper += t.weight as u64;
+        bucket < upper
+    })
+}
+
+/// Returns the value that keeps a client on the same target, if any.
+fn sticky_value(rule: &SplitRule, headers: &HeaderMap) -> Option<String> {
+    if let Some(ref name) = rule.sticky_header {
+        if let Some(value) = headers.get(name.as_str()).and_then(|v| v.to_str().ok()) {
+            return Some(value.to_string());
+        }
+    }
+    let cookie_name = rule.sticky_cookie.as_ref()?;
+    headers
+        .get_all(warp::http::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| name == cookie_name)
+        .map(|(_, value)| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::HeaderValue;
+
+    fn target(name: &str, weight: u32) -> SplitTarget {
+        SplitTarget {
+            name: name.to_string(),
+            url: format!("https://{}.example.com", name),
+            weight,
+        }
+    }
+
+    fn rule(targets: Vec<SplitTarget>) -> SplitRule {
+        SplitRule {
+            method: None,
+            path: "/v1/*".to_string(),
+            sticky_cookie: Some("session_id".to_string()),
+            sticky_header: Some("x-user".to_string()),
+            targets,
+        }
+    }
+
+    fn choose<'a>(rules: &'a [SplitRule], pairs: &[(&'static str, &str)]) -> Option<&'a str> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        choose_target(rules, &Method::GET, "/v1/users", &headers).map(|t| t.name.as_str())
+    }
+
+    #[test]
+    fn spreads_buckets_by_weight() {
+        let targets = [target("canary", 10), target("stable", 90)];
+        let at = |bucket| target_at(&targets, bucket).map(|t| t.name.as_str());
+        assert_eq!(at(0), Some("canary"));
+        assert_eq!(at(9), Some("canary"));
+        assert_eq!(at(10), Some("stable"));
+        assert_eq!(at(99), Some("stable"));
+        assert_eq!(at(100), None);
+    }
+
+    #[test]
+    fn sums_large_weights_without_overflow() {
+        let targets = [target("a", u32::MAX), target("b", u32::MAX)];
+        assert_eq!(total_weight(&targets), 2 * u32::MAX as u64);
+        let at = |bucket| target_at(&targets, bucket).map(|t| t.name.as_str());
+        assert_eq!(at(u32::MAX as u64), Some("b"));
+        let rules = [rule(targets.to_vec())];
+        assert!(choose(&rules, &[]).is_some());
+    }
+
+    #[test]
+    fn honors_the_override_header() {
+        let rules = [rule(vec![target("canary", 0), target("stable", 1)])];
+        assert_eq!(
+            choose(&rules, &[(OVERRIDE_HEADER, " Canary ")]),
+            Some("canary")
+        );
+        // An unknown name falls back to the weights.
+        assert_eq!(choose(&rules, &[(OVERRIDE_HEADER, "beta")]), Some("stable"));
+    }
+
+    #[test]
+    fn keeps_sticky_clients_on_one_target() {
+        let rules = [rule(vec![target("a", 1), target("b", 1), target("c", 1)])];
+        for user in ["alice", "bob", "carol", "dave"] {
+            let by_header = choose(&rules, &[("x-user", user)]);
+            let cookie = format!("theme=dark; session_id={}", user);
+            let by_cookie = choose(&rules, &[("cookie", cookie.as_str())]);
+            assert!(by_header.is_some());
+            assert_eq!(by_header, by_cookie);
+            for _ in 0..10 {
+                assert_eq!(choose(&rules, &[("x-user", user)]), by_header);
+            }
+        }
+    }
+
+    #[test]
+    fn uses_the_first_target_when_no_weight_is_set() {
+        let rules = [rule(vec![target("a", 0), target("b", 0)])];
+        assert_eq!(choose(&rules, &[]), Some("a"));
+        assert_eq!(choose(&rules, &[("x-user", "alice")]), Some("a"));
+    }
+
+    #[test]
+    fn ignores_other_routes() {
+        let rules = [rule(vec![target("a", 1)])];
+        let headers = HeaderMap::new();
+        assert!(choose_target(&rules, &Method::GET, "/v2/users", &headers).is_none());
+    }
+}
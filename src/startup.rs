@@ -0,0 +1,198 @@
+use log::{error, warn};
+use std::fs;
+use std::path::Path;
+use url::Url;
+use warp::http::header::HeaderName;
+use warp::http::HeaderValue;
+
+/// Where a startup problem lies. Each category has its own exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    /// Missing or malformed options: URLs, extra headers, config file, profile.
+    Options,
+    /// Mock config files that can't be read or parsed, or hold broken mocks.
+    Mocks,
+    /// Directories and files the proxy can't read or write.
+    Filesystem,
+}
+
+impl Category {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Category::Options => 2,
+            Category::Mocks => 3,
+            Category::Filesystem => 4,
+        }
+    }
+}
+
+/// Problems found while setting up the proxy, reported all at once so a
+/// broken setup can be fixed in one go.
+#[derive(Debug, Default)]
+pub struct Problems {
+    problems: Vec<(Category, String)>,
+}
+
+impl Problems {
+    pub fn push(&mut self, category: Category, message: impl Into<String>) {
+        self.problems.push((category, message.into()));
+    }
+
+    /// The problem messages, in the order they were found.
+    pub fn messages(&self) -> impl Iterator<Item = &str> {
+        self.problems.iter().map(|(_, message)| message.as_str())
+    }
+
+    /// The exit code of the first category that has a problem, if any.
+    fn exit_code(&self) -> Option<i32> {
+        self.problems
+            .iter()
+            .map(|(category, _)| *category)
+            .min()
+            .map(Category::exit_code)
+    }
+
+    /// Logs every problem, then exits with the code of the first category
+    /// that has one. With `lenient`, the problems are only warnings and the
+    /// proxy starts anyway.
+    pub fn finish(self, lenient: bool) {
+        let Some(code) = self.exit_code() else {
+            return;
+        };
+        for message in self.messages() {
+            if lenient {
+                warn!("{}", message);
+            } else {
+                error!("{}", message);
+            }
+        }
+        if lenient {
+            warn!(
+                "Starting despite {} configuration problem(s) (--lenient)",
+                self.problems.len()
+            );
+            return;
+        }
+        error!(
+            "Found {} configuration problem(s), not starting (pass --lenient to start anyway)",
+            self.problems.len()
+        );
+        std::process::exit(code);
+    }
+}
+
+/// Checks that an option holds an absolute http(s) URL.
+pub fn check_url(name: &str, url: &str) -> Result<(), String> {
+    let parsed = Url::parse(url).map_err(|e| format!("Invalid {} {:?}: {}", name, url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+        return Err(format!(
+            "Invalid {} {:?}: must be an http(s) URL like http://localhost:6969",
+            name, url
+        ));
+    }
+    Ok(())
+}
+
+/// Checks an `--extra-header` line.
+pub fn check_extra_header(header: &str) -> Result<(), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("Extra header not in 'Key: Value' format: {}", header))?;
+    HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("Invalid extra header name {:?}", name.trim()))?;
+    HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("Invalid extra header value in {:?}", header))?;
+    Ok(())
+}
+
+/// Creates a directory if it doesn't exist yet, then checks that it can be
+/// written to by creating and removing a probe file in it.
+pub fn ensure_writable_dir(name: &str, dir: &str) -> Result<(), String> {
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir)
+        .map_err(|e| format!("Cannot create {} {}: {}", name, dir.display(), e))?;
+    let probe = dir.join(".proxxyy-write-test");
+    fs::write(&probe, b"")
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| format!("Cannot write to {} {}: {}", name, dir.display(), e))
+}
+
+/// Creates a file if it doesn't exist yet, then checks that it can be
+/// appended to.
+pub fn ensure_writable_file(name: &str, file: &str) -> Result<(), String> {
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)
+        .map(|_| ())
+        .map_err(|e| format!("Cannot write to {} {}: {}", name, file, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn checks_urls() {
+        assert!(check_url("target URL", "https://api.example.com/v1").is_ok());
+        assert!(check_url("target URL", "http://localhost:6969").is_ok());
+        for url in [
+            "localhost:6969",
+            "ftp://example.com",
+            "http://",
+            "not a url",
+        ] {
+            let err = check_url("target URL", url).unwrap_err();
+            assert!(err.starts_with("Invalid target URL "), "{}", err);
+        }
+    }
+
+    #[test]
+    fn checks_extra_headers() {
+        assert!(check_extra_header("Authorization: Bearer abc").is_ok());
+        assert!(check_extra_header("X-Empty:").is_ok());
+        assert_eq!(
+            check_extra_header("Authorization Bearer").unwrap_err(),
+            "Extra header not in 'Key: Value' format: Authorization Bearer"
+        );
+        assert_eq!(
+            check_extra_header("Bad Name: 1").unwrap_err(),
+            "Invalid extra header name \"Bad Name\""
+        );
+        assert_eq!(
+            check_extra_header("X-A: a\u{7f}b").unwrap_err(),
+            "Invalid extra header value in \"X-A: a\\u{7f}b\""
+        );
+    }
+
+    #[test]
+    fn exits_with_the_code_of_the_first_category() {
+        let mut problems = Problems::default();
+        assert_eq!(problems.exit_code(), None);
+        problems.push(Category::Filesystem, "a");
+        assert_eq!(problems.exit_code(), Some(4));
+        problems.push(Category::Mocks, "b");
+        assert_eq!(problems.exit_code(), Some(3));
+        problems.push(Category::Options, "c");
+        assert_eq!(problems.exit_code(), Some(2));
+        assert_eq!(problems.messages().collect::<Vec<_>>(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn creates_writable_directories() {
+        let root = TempDir::new("startup");
+        let dir = root.join("a/b");
+        ensure_writable_dir("cache directory", &dir.to_string_lossy()).unwrap();
+        assert!(dir.is_dir());
+        assert_eq!(
+            fs::read_dir(&dir).unwrap().count(),
+            0,
+            "the probe is removed"
+        );
+
+        let file = root.write("file", "");
+        let err = ensure_writable_dir("cache directory", &file.to_string_lossy()).unwrap_err();
+        assert!(err.starts_with("Cannot create cache directory "), "{}", err);
+    }
+}
@@ -0,0 +1,287 @@
+use bytes::Bytes;
+use log::{error, info};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use warp::http::header;
+use warp::http::{HeaderMap, Method, Response, StatusCode};
+
+/// Files tried, in order, when a request points at a directory.
+const INDEX_FILES: &[&str] = &["index.html", "index.htm"];
+
+/// A URL prefix served from a local directory (`--static-dir /assets=./dist`).
+#[derive(Debug, Clone)]
+pub struct StaticDir {
+    pub prefix: String,
+    pub dir: PathBuf,
+}
+
+impl StaticDir {
+    /// Parses a `PREFIX=DIR` mapping.
+    pub fn parse(spec: &str) -> Result<StaticDir, String> {
+        let (prefix, dir) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("static dir not in 'PREFIX=DIR' format: {}", spec))?;
+        let prefix = format!("/{}", prefix.trim().trim_matches('/'));
+        let dir = PathBuf::from(dir.trim());
+        if !dir.is_dir() {
+            return Err(format!("static dir {} is not a directory", dir.display()));
+        }
+        Ok(StaticDir { prefix, dir })
+    }
+
+    /// Maps a request path to a file inside the directory, if one exists.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let rest = if self.prefix == "/" {
+            path
+        } else {
+            let rest = path.strip_prefix(&self.prefix)?;
+            if !rest.is_empty() && !rest.starts_with('/') {
+                return None;
+            }
+            rest
+        };
+
+        let decoded = percent_encoding::percent_decode_str(rest)
+            .decode_utf8()
+            .ok()?;
+        let mut file = self.dir.clone();
+        for segment in decoded.split('/').filter(|s| !s.is_empty()) {
+            // Never let a request climb out of the served directory.
+            if segment == ".." || segment.contains('\\') {
+                return None;
+            }
+            file.push(segment);
+        }
+
+        if file.is_dir() {
+            return INDEX_FILES
+                .iter()
+                .map(|index| file.join(index))
+                .find(|index| index.is_file());
+        }
+        Some(file).filter(|f| f.is_file())
+    }
+}
+
+/// Finds the file serving a GET or HEAD request, trying the longest prefix first.
+/// Returns `None` when the request should fall through to mocks or the upstream.
+pub fn find(dirs: &[StaticDir], method: &Method, path: &str) -> Option<PathBuf> {
+    if method != Method::GET && method != Method::HEAD {
+        return None;
+    }
+    let mut dirs: Vec<&StaticDir> = dirs.iter().collect();
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.prefix.len()));
+    dirs.iter().find_map(|d| d.resolve(path))
+}
+
+/// Serves a file, honoring conditional (`If-None-Match`, `If-Modified-Since`)
+/// and single-range (`Range: bytes=...`) requests.
+pub fn serve(file: &Path, method: &Method, headers: &HeaderMap) -> Response<Bytes> {
+    let contents = match fs::read(file) {
+        Ok(contents) => contents,
+        Err(e) => {
+            error!("Error reading {}: {}", file.display(), e);
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .header(header::CONTENT_TYPE, "text/plain")
+                .body(Bytes::from(format!("Error reading {}", file.display())))
+                .expect("failed to build error response");
+        }
+    };
+    info!("Serving static file {}", file.display());
+
+    let modified = fs::metadata(file).and_then(|m| m.modified()).ok();
+    let mtime = modified
+        .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    let etag = format!("W/\"{:x}-{:x}\"", contents.len(), mtime);
+    let last_modified = modified.map(httpdate::fmt_http_date);
+    let content_type = mime_guess::from_path(file)
+        .first()
+        .map(|m| m.essence_str().to_string())
+        .or_else(|| crate::content_type::sniff(None, &contents))
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    let mut builder = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(ref last_modified) = last_modified {
+        builder = builder.header(header::LAST_MODIFIED, last_modified);
+    }
+
+    if is_not_modified(headers, &etag, modified) {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Bytes::new())
+            .expect("failed to build static response");
+    }
+
+    builder = builder.header(header::CONTENT_TYPE, content_type);
+    let total = contents.len();
+    let (status, body) = match range(headers, &etag, total) {
+        Some(Ok((start, end))) => {
+            builder = builder.header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, total),
+            );
+            (
+                StatusCode::PARTIAL_CONTENT,
+                Bytes::copy_from_slice(&contents[start..=end]),
+            )
+        }
+        Some(Err(())) => {
+            return builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", total))
+                .body(Bytes::new())
+                .expect("failed to build static response");
+        }
+        None => (StatusCode::OK, Bytes::from(contents)),
+    };
+
+    let body = if method == Method::HEAD {
+        builder = builder.header(header::CONTENT_LENGTH, body.len());
+        Bytes::new()
+    } else {
+        body
+    };
+    builder
+        .status(status)
+        .body(body)
+        .expect("failed to build static response")
+}
+
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+    {
+        return if_none_match.split(',').any(|tag| {
+            tag.trim() == "*"
+                || tag.trim().trim_start_matches("W/") == etag.trim_start_matches("W/")
+        });
+    }
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    match (since, modified) {
+        // HTTP dates have a one second resolution.
+        (Some(since), Some(modified)) => modified
+            .duration_since(since)
+            .map_or(true, |d| d.as_secs() == 0),
+        _ => false,
+    }
+}
+
+/// Parses a single `Range: bytes=...` header into an inclusive byte range.
+/// Returns `None` to serve the whole file, `Some(Err(()))` if unsatisfiable.
+fn range(headers: &HeaderMap, etag: &str, total: usize) -> Option<Result<(usize, usize), ()>> {
+    let spec = headers.get(header::RANGE)?.to_str().ok()?;
+    // A stale If-Range means the client's partial copy is outdated: send everything.
+    if let Some(if_range) = headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) {
+        if if_range.trim() != etag {
+            return None;
+        }
+    }
+    let spec = spec.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().ok()?;
+            if suffix == 0 {
+                return Some(Err(()));
+            }
+            (total.saturating_sub(suffix), total.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, total.checked_sub(1)?),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<usize>().ok()?.min(total.checked_sub(1)?),
+        ),
+    };
+    if start > end || start >= total {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::headers;
+    use std::time::Duration;
+    use warp::http::HeaderValue;
+
+    const ETAG: &str = "W/\"a-1\"";
+
+    fn range_of(spec: &str, total: usize) -> Option<Result<(usize, usize), ()>> {
+        range(&headers(&[(header::RANGE, spec)]), ETAG, total)
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(range_of("bytes=0-9", 100), Some(Ok((0, 9))));
+        assert_eq!(range_of("bytes=90-", 100), Some(Ok((90, 99))));
+        assert_eq!(range_of("bytes=-10", 100), Some(Ok((90, 99))));
+        // An end past the file is cut to its last byte, a suffix to its start.
+        assert_eq!(range_of("bytes=50-500", 100), Some(Ok((50, 99))));
+        assert_eq!(range_of("bytes=-500", 100), Some(Ok((0, 99))));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(range_of("bytes=100-", 100), Some(Err(())));
+        assert_eq!(range_of("bytes=9-3", 100), Some(Err(())));
+        assert_eq!(range_of("bytes=-0", 100), Some(Err(())));
+    }
+
+    #[test]
+    fn serves_everything_for_ranges_it_does_not_handle() {
+        assert_eq!(range(&HeaderMap::new(), ETAG, 100), None);
+        assert_eq!(range_of("bytes=0-1,5-6", 100), None);
+        assert_eq!(range_of("items=0-9", 100), None);
+        assert_eq!(range_of("bytes=a-b", 100), None);
+        assert_eq!(range_of("bytes=0-", 0), None);
+    }
+
+    #[test]
+    fn honors_if_range() {
+        let current = headers(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, ETAG)]);
+        assert_eq!(range(&current, ETAG, 100), Some(Ok((0, 9))));
+        let stale = headers(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, "\"old\"")]);
+        assert_eq!(range(&stale, ETAG, 100), None);
+    }
+
+    #[test]
+    fn matches_if_none_match() {
+        let modified = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        for tags in [ETAG, "\"a-1\"", "\"other\", W/\"a-1\"", "*"] {
+            let headers = headers(&[(header::IF_NONE_MATCH, tags)]);
+            assert!(is_not_modified(&headers, ETAG, modified), "{}", tags);
+        }
+        let other = headers(&[(header::IF_NONE_MATCH, "\"other\"")]);
+        assert!(!is_not_modified(&other, ETAG, modified));
+    }
+
+    #[test]
+    fn compares_if_modified_since_to_the_second() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_millis(1_000_000_500);
+        let at = |secs: u64| {
+            let date = httpdate::fmt_http_date(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+            headers(&[(header::IF_MODIFIED_SINCE, date.as_str())])
+        };
+        assert!(is_not_modified(&at(1_000_000), ETAG, Some(modified)));
+        assert!(is_not_modified(&at(1_000_100), ETAG, Some(modified)));
+        assert!(!is_not_modified(&at(999_999), ETAG, Some(modified)));
+        assert!(!is_not_modified(&at(1_000_000), ETAG, None));
+        // If-None-Match wins over If-Modified-Since.
+        let mut both = at(1_000_000);
+        both.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert!(!is_not_modified(&both, ETAG, Some(modified)));
+    }
+}
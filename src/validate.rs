@@ -0,0 +1,341 @@
+use crate::mock::Mock;
+use crate::mock_config::{self, MockFile};
+use colored::Colorize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use warp::http::Method;
+
+/// A problem found in a mock config file.
+struct Problem {
+    file: PathBuf,
+    line: Option<usize>,
+    message: String,
+}
+
+/// A mock with the place it was defined.
+struct Located {
+    mock: Mock,
+    file: PathBuf,
+    line: Option<usize>,
+}
+
+impl Located {
+    fn location(&self) -> String {
+        location(&self.file, self.line)
+    }
+}
+
+fn location(file: &Path, line: Option<usize>) -> String {
+    match line {
+        Some(line) => format!("{}:{}", file.display(), line),
+        None => file.display().to_string(),
+    }
+}
+
+/// Checks mock config files (and the files they include) without starting the
+/// proxy, printing every problem as `file:line: message`.
+///
+/// Returns the process exit code: 0 if every file is fine, 1 otherwise.
+pub fn run(files: &[String]) -> i32 {
+    let (problems, checked) = check_files(files);
+    for problem in &problems {
+        println!(
+            "{}: {}: {}",
+            location(&problem.file, problem.line).bold(),
+            "error".red(),
+            problem.message
+        );
+    }
+    if problems.is_empty() {
+        println!("{} mock file(s) OK", checked);
+        0
+    } else {
+        println!("{} problem(s) in {} mock file(s)", problems.len(), checked);
+        1
+    }
+}
+
+/// Checks the files and their includes. Returns the problems found and how
+/// many files were checked.
+fn check_files(files: &[String]) -> (Vec<Problem>, usize) {
+    let mut problems = Vec::new();
+    let mut visited = HashSet::new();
+    let mut checked = 0;
+    // Every mock in match order, as the proxy would merge them: later files
+    // take precedence over earlier ones.
+    let mut merged = Vec::new();
+    for file in files.iter().rev() {
+        let mut mocks = Vec::new();
+        checked += validate_file(Path::new(file), &mut visited, &mut problems, &mut mocks);
+        merged.extend(mocks);
+    }
+    check_shadowing(&merged, &mut problems);
+    (problems, checked)
+}
+
+/// Validates one file and its includes, adding their mocks to `mocks` in
+/// match order. Returns how many files were checked.
+fn validate_file(
+    path: &Path,
+    visited: &mut HashSet<PathBuf>,
+    problems: &mut Vec<Problem>,
+    mocks: &mut Vec<Located>,
+) -> usize {
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if !visited.insert(canonical) {
+        return 0;
+    }
+    let mut report = |line: Option<usize>, message: String| {
+        problems.push(Problem {
+            file: path.to_path_buf(),
+            line,
+            message,
+        })
+    };
+
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            report(None, format!("cannot read file: {}", err));
+            return 1;
+        }
+    };
+    let parsed: MockFile = match mock_config::parse(path, &contents) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            report(None, format!("cannot parse file: {}", err.trim_end()));
+            return 1;
+        }
+    };
+
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    let lines = mock_lines(&contents, &parsed.mocks);
+    for (mock, line) in parsed.mocks.into_iter().zip(lines) {
+        for message in check_mock(&mock, base_dir) {
+            report(line, message);
+        }
+        mocks.push(Located {
+            mock,
+            file: path.to_path_buf(),
+            line,
+        });
+    }
+
+    let mut includes = Vec::new();
+    for pattern in &parsed.include {
+        let pattern = base_dir.join(pattern).to_string_lossy().into_owned();
+        let mut matches: Vec<PathBuf> = match glob::glob(&pattern) {
+            Ok(paths) => paths.flatten().collect(),
+            Err(err) => {
+                report(
+                    None,
+                    format!("invalid include pattern {}: {}", pattern, err),
+                );
+                continue;
+            }
+        };
+        if matches.is_empty() {
+            report(None, format!("include {} matches no files", pattern));
+        }
+        matches.sort();
+        includes.extend(matches);
+    }
+
+    // A file's own mocks come first, then its includes, the last one first.
+    let mut checked = 1;
+    for included in includes.iter().rev() {
+        checked += validate_file(included, visited, problems, mocks);
+    }
+    checked
+}
+
+/// Reports mocks that can never be served: the first matching mock wins, so
+/// a mock covered by one ahead of it in match order is unreachable, whichever
+/// files the two come from.
+fn check_shadowing(mocks: &[Located], problems: &mut Vec<Problem>) {
+    for (i, later) in mocks.iter().enumerate() {
+        let Some(earlier) = mocks[..i].iter().find(|e| shadows(&e.mock, &later.mock)) else {
+            continue;
+        };
+        let mock = &later.mock;
+        let message = if earlier.mock.path.eq_ignore_ascii_case(&mock.path) {
+            format!(
+                "duplicate mock {} {}: the one at {} always wins",
+                mock.method,
+                mock.path,
+                earlier.location()
+            )
+        } else {
+            format!(
+                "unreachable mock {} {}: shadowed by {} {} at {}",
+                mock.method,
+                mock.path,
+                earlier.mock.method,
+                earlier.mock.path,
+                earlier.location()
+            )
+        };
+        problems.push(Problem {
+            file: later.file.clone(),
+            line: later.line,
+            message,
+        });
+    }
+}
+
+/// Checks a single mock on its own.
+fn check_mock(mock: &Mock, base_dir: &Path) -> Vec<String> {
+    let mut messages = Vec::new();
+    if Method::from_bytes(mock.method.to_ascii_uppercase().as_bytes()).is_err() {
+        messages.push(format!("invalid method {:?}", mock.method));
+    }
+    if !mock.path.starts_with('/') {
+        messages.push(format!("path {:?} does not start with '/'", mock.path));
+    }
+    // Status, headers and body are checked the same way as at startup.
+    if let Err(err) = mock.clone().prepare(base_dir) {
+        messages.push(err);
+    }
+    messages
+}
+
+/// Whether `earlier` answers every request `later` answers.
+fn shadows(earlier: &Mock, later: &Mock) -> bool {
+    if !earlier.method.eq_ignore_ascii_case(&later.method) {
+        return false;
+    }
+    if earlier.path.eq_ignore_ascii_case(&later.path) {
+        return true;
+    }
+    let is_param = |s: &str| s.starts_with('{') && s.ends_with('}');
+    let earlier: Vec<&str> = earlier.path.split('/').collect();
+    let later: Vec<&str> = later.path.split('/').collect();
+    earlier.len() == later.len()
+        && earlier.iter().zip(&later).all(|(e, l)| {
+            if is_param(e) {
+                !l.is_empty()
+            } else {
+                !is_param(l) && e.eq_ignore_ascii_case(l)
+            }
+        })
+}
+
+/// The line of each mock's `path`, found by looking for the paths in file
+/// order. Works the same for TOML, YAML and JSON.
+fn mock_lines(contents: &str, mocks: &[Mock]) -> Vec<Option<usize>> {
+    let mut cursor = 0;
+    mocks
+        .iter()
+        .map(|mock| {
+            let start = find_value(&contents[cursor..], &mock.path)? + cursor;
+            cursor = start + mock.path.len();
+            Some(contents[..start].matches('\n').count() + 1)
+        })
+        .collect()
+}
+
+/// Finds `value` as a whole value, not as the prefix of a longer one.
+fn find_value(haystack: &str, value: &str) -> Option<usize> {
+    let mut from = 0;
+    while let Some(pos) = haystack[from..].find(value) {
+        let start = from + pos;
+        let end = start + value.len();
+        let next = haystack[end..].chars().next();
+        if next.is_none_or(|c| matches!(c, '"' | '\'' | ',' | '}' | ' ' | '\t' | '\r' | '\n')) {
+            return Some(start);
+        }
+        from = end;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    /// Writes mock files to a fresh temp directory and checks them, in order.
+    /// Returns each problem as `file:line: message`, with bare file names.
+    fn check(files: &[(&str, &str)]) -> Vec<String> {
+        let dir = TempDir::new("validate");
+        let paths: Vec<String> = files
+            .iter()
+            .map(|(file, contents)| dir.write(file, contents).to_string_lossy().into_owned())
+            .collect();
+        let (problems, checked) = check_files(&paths);
+        assert_eq!(checked, files.len());
+        let prefix = format!("{}/", dir.display());
+        problems
+            .iter()
+            .map(|p| format!("{}: {}", location(&p.file, p.line), p.message).replace(&prefix, ""))
+            .collect()
+    }
+
+    fn mock(method: &str, path: &str) -> Mock {
+        serde_json::from_value(serde_json::json!({ "method": method, "path": path })).unwrap()
+    }
+
+    #[test]
+    fn flags_a_broader_mock_in_a_later_file() {
+        let base = "[[mocks]]\nmethod = \"GET\"\npath = \"/v1/health\"\n\n\
+                    [[mocks]]\nmethod = \"GET\"\npath = \"/v1/users/me\"\nbody = \"{}\"\n";
+        let local = "# overrides\n[[mocks]]\nmethod = \"GET\"\npath = \"/v1/users/{id}\"\n";
+        // Later files win, so local.toml's template hides base.toml's mock.
+        assert_eq!(
+            check(&[("base.toml", base), ("local.toml", local)]),
+            ["base.toml:7: unreachable mock GET /v1/users/me: shadowed by GET /v1/users/{id} at local.toml:4"]
+        );
+    }
+
+    #[test]
+    fn reports_duplicates_at_both_locations() {
+        let yaml = "mocks:\n  - method: GET\n    path: /v1/items\n";
+        let json = r#"{"mocks": [
+            {"method": "get", "path": "/v1/items"}
+        ]}"#;
+        assert_eq!(
+            check(&[("a.yaml", yaml), ("b.json", json)]),
+            ["a.yaml:3: duplicate mock GET /v1/items: the one at b.json:2 always wins"]
+        );
+    }
+
+    #[test]
+    fn leaves_distinct_mocks_alone() {
+        let toml = "[[mocks]]\nmethod = \"GET\"\npath = \"/v1/users/{id}\"\n\n\
+                    [[mocks]]\nmethod = \"POST\"\npath = \"/v1/users/me\"\n\n\
+                    [[mocks]]\nmethod = \"GET\"\npath = \"/v1/users/{id}/orders\"\n\n\
+                    [[mocks]]\nmethod = \"GET\"\npath = \"/v1/orders/{id}\"\n";
+        assert_eq!(check(&[("mocks.toml", toml)]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn only_broader_templates_shadow() {
+        assert!(shadows(
+            &mock("GET", "/v1/{kind}/{id}"),
+            &mock("get", "/v1/users/{id}")
+        ));
+        assert!(!shadows(
+            &mock("GET", "/v1/users/{id}"),
+            &mock("GET", "/v1/{kind}/7")
+        ));
+        assert!(!shadows(
+            &mock("GET", "/v1/users/{id}"),
+            &mock("GET", "/v1/users/")
+        ));
+        assert!(!shadows(
+            &mock("GET", "/v1/users"),
+            &mock("DELETE", "/v1/users")
+        ));
+    }
+
+    #[test]
+    fn finds_whole_values_in_file_order() {
+        let contents = "path = \"/v1/users/me\"\npath = \"/v1/users\"\npath = \"/v1/users\"\n";
+        let mocks = [mock("GET", "/v1/users"), mock("POST", "/v1/users")];
+        assert_eq!(mock_lines(contents, &mocks), [Some(2), Some(3)]);
+        assert_eq!(find_value("'/a/b', '/a'", "/a"), Some(9));
+        assert_eq!(find_value("/a/b", "/a"), None);
+        assert_eq!(mock_lines("", &[mock("GET", "/gone")]), [None]);
+    }
+}